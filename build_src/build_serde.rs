@@ -10,6 +10,9 @@ pub struct Profile {
     pub fifo: FifoConfig,
     #[serde(default)]
     pub reg_bit_size: RegBitSize,
+    /// Whether the core tolerates mixing 8-bit and 32-bit accesses to the endpoint FIFOs.
+    #[serde(default)]
+    pub fifo_mixed_width: bool,
     pub endpoints: Vec<EndpointConfig>,
     #[serde(default = "Vec::new")]
     pub patches: Vec<Patch>,
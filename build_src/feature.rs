@@ -57,6 +57,10 @@ impl FeatureGenerator {
             FifoConfig::Dynamic(_) => (),
         }
 
+        if profile.fifo_mixed_width {
+            features.push("_fifo-mixed-width".to_string());
+        }
+
         if let Some(_) = profile.base_address {
             features.push("_gen-usb-instance".to_string());
         }
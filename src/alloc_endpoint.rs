@@ -1,6 +1,6 @@
 use embassy_usb_driver::{Direction, EndpointType};
 
-use crate::info::ENDPOINTS;
+use crate::info::{EpDirection, ENDPOINTS};
 #[cfg(not(feature = "_fixed-fifo-size"))]
 use crate::info::TOTAL_FIFO_SIZE;
 
@@ -9,6 +9,17 @@ pub struct EndpointConfig {
     pub ep_type: EndpointType,
     pub tx_max_packet_size: u16,
     pub rx_max_packet_size: u16,
+    /// Number of packets the core splits (TX) or amalgamates (RX) per FIFO load,
+    /// programmed into MAXP as `m - 1`.
+    pub tx_packet_multiplier: u8,
+    pub rx_packet_multiplier: u8,
+    /// Let the core set TxPktRdy once a full FIFO load is written (TXCSRH.AutoSet).
+    pub tx_auto_set: bool,
+    /// Let the core clear RxPktRdy once a full FIFO load is read (RXCSRH.AutoClear).
+    pub rx_auto_clear: bool,
+    /// Double packet buffering, the endpoint FIFO holds two FIFO loads.
+    pub tx_double_buffered: bool,
+    pub rx_double_buffered: bool,
     
     #[cfg(not(feature = "_fixed-fifo-size"))]
     pub tx_fifo_size_bits: u8,
@@ -28,24 +39,731 @@ pub struct EndpointData {
     pub used_rx: bool,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum EndpointAllocError {
     EndpointOverflow,
     InvalidEndpoint,
     EpDirNotSupported,
     EpUsed,
     MaxPacketSizeBiggerThanEpFifoSize,
+    /// The control endpoint max packet size must be 8, 16, 32 or 64.
+    InvalidControlMaxPacketSize,
+    /// The max packet size is above the USB limit for the endpoint type and speed.
+    MaxPacketSizeExceedsSpeedLimit,
     #[cfg(not(feature = "_fixed-fifo-size"))]
     BufferOverflow,
+    /// Compacting the FIFO RAM would move an IN endpoint FIFO holding data, or
+    /// the FIFO of an enabled OUT endpoint.
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    FifoBusy,
+}
+
+/// Optional features requested for an endpoint, granted as far as the FIFO allows.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocOptions {
+    /// Largest number of packets per FIFO load (bulk split/amalgamation).
+    pub max_packet_multiplier: u8,
+    /// Endpoint indexes (bit `n` for endpoint `n`) asking for double packet buffering.
+    pub double_buffered_endpoints: u16,
+    /// The device may run at high speed, allowing the high-speed max packet sizes.
+    pub high_speed: bool,
+    /// Accept endpoints beyond the free FIFO RAM, their FIFO is placed by
+    /// [`place_fifo`] when they are enabled.
+    ///
+    /// Endpoints of different alternate settings are never enabled together, so
+    /// they can share the FIFO RAM.
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    pub overcommit_fifo: bool,
+    /// Accept both directions on a shared FIFO endpoint requested by address.
+    ///
+    /// Only one direction can be enabled at a time, the FIFO is switched to it when
+    /// it is enabled, e.g. by an alternate setting. Automatic allocation never
+    /// shares an endpoint.
+    #[cfg(feature = "_ep-shared-fifo")]
+    pub direction_switching: bool,
+}
+
+impl Default for AllocOptions {
+    fn default() -> Self {
+        Self {
+            max_packet_multiplier: 1,
+            double_buffered_endpoints: 0,
+            high_speed: false,
+            #[cfg(not(feature = "_fixed-fifo-size"))]
+            overcommit_fifo: false,
+            #[cfg(feature = "_ep-shared-fifo")]
+            direction_switching: false,
+        }
+    }
+}
+
+/// EP0 always uses the first 64 bytes of the FIFO RAM.
+#[cfg(not(feature = "_fixed-fifo-size"))]
+const EP0_FIFO_SIZE_8BYTES: u16 = 64 / 8;
+
+#[cfg(not(feature = "_fixed-fifo-size"))]
+const FIFO_END_8BYTES: u16 = TOTAL_FIFO_SIZE / 8;
+
+/// FIFO RAM allocator for dynamic FIFO cores.
+///
+/// TX FIFOs are packed upwards right after EP0, RX FIFOs downwards from the end
+/// of the FIFO RAM, so each direction has its own region and the free space stays
+/// in one piece between them. Every FIFO is a power-of-two block, twice as large
+/// with double packet buffering.
+#[cfg(not(feature = "_fixed-fifo-size"))]
+#[derive(Debug, Clone, Copy)]
+pub struct FifoAllocator {
+    /// End of the TX region, in 8-byte units.
+    tx_end_8bytes: u16,
+    /// Start of the RX region, in 8-byte units.
+    rx_start_8bytes: u16,
+}
+
+#[cfg(not(feature = "_fixed-fifo-size"))]
+impl FifoAllocator {
+    pub const fn new() -> Self {
+        Self {
+            tx_end_8bytes: EP0_FIFO_SIZE_8BYTES,
+            rx_start_8bytes: FIFO_END_8BYTES,
+        }
+    }
+
+    /// Reserves `size_8bytes` in the region of `direction`, returns its address.
+    fn alloc(&mut self, direction: Direction, size_8bytes: u16) -> Option<u16> {
+        if size_8bytes > self.rx_start_8bytes - self.tx_end_8bytes {
+            return None;
+        }
+        match direction {
+            Direction::In => {
+                let addr = self.tx_end_8bytes;
+                self.tx_end_8bytes += size_8bytes;
+                Some(addr)
+            }
+            Direction::Out => {
+                self.rx_start_8bytes -= size_8bytes;
+                Some(self.rx_start_8bytes)
+            }
+        }
+    }
+
+    /// Reports how the FIFO RAM is used by the endpoints in `alloc`.
+    ///
+    /// Over-committed endpoints are counted in `used_bytes`, which may then exceed
+    /// `total_bytes`.
+    pub fn usage(&self, alloc: &[EndpointData; ENDPOINTS.len()]) -> FifoUsage {
+        let mut used_bytes = EP0_FIFO_SIZE_8BYTES * 8;
+        let mut wasted_bytes = 0;
+        for ep in alloc.iter().skip(1) {
+            let conf = &ep.ep_conf;
+            let dirs = [
+                (
+                    ep.used_tx,
+                    conf.tx_fifo_size_bits,
+                    conf.tx_max_packet_size,
+                    conf.tx_packet_multiplier,
+                    conf.tx_double_buffered,
+                ),
+                (
+                    ep.used_rx,
+                    conf.rx_fifo_size_bits,
+                    conf.rx_max_packet_size,
+                    conf.rx_packet_multiplier,
+                    conf.rx_double_buffered,
+                ),
+            ];
+            for (used, size_bits, mps, multiplier, double_buffered) in dirs {
+                if !used {
+                    continue;
+                }
+                let buffers = if double_buffered { 2 } else { 1 };
+                let block = (1u16 << size_bits) * buffers;
+                used_bytes += block;
+                wasted_bytes += block - mps * multiplier as u16 * buffers;
+            }
+        }
+        let regions = alloc.iter().skip(1).flat_map(|ep| {
+            let tx = ep.used_tx.then(|| fifo_region(&ep.ep_conf, Direction::In));
+            let rx = ep.used_rx.then(|| fifo_region(&ep.ep_conf, Direction::Out));
+            tx.into_iter().chain(rx)
+        });
+        FifoUsage {
+            total_bytes: TOTAL_FIFO_SIZE,
+            used_bytes,
+            wasted_bytes,
+            free_bytes: (self.rx_start_8bytes - self.tx_end_8bytes) * 8,
+            largest_free_bytes: largest_free_block(regions),
+        }
+    }
+}
+
+#[cfg(not(feature = "_fixed-fifo-size"))]
+impl Default for FifoAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// FIFO RAM usage and fragmentation report, see [`FifoAllocator::usage`].
+#[cfg(not(feature = "_fixed-fifo-size"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FifoUsage {
+    /// Size of the FIFO RAM.
+    pub total_bytes: u16,
+    /// Bytes reserved for endpoint FIFOs, EP0 included.
+    pub used_bytes: u16,
+    /// Reserved bytes lost to rounding FIFOs up to a power of two (internal fragmentation).
+    pub wasted_bytes: u16,
+    /// Bytes not reserved by any endpoint.
+    pub free_bytes: u16,
+    /// Largest contiguous free block, less than `free_bytes` when the free space is fragmented.
+    pub largest_free_bytes: u16,
+}
+
+/// Endpoint FIFOs holding FIFO RAM at runtime, bit `n` for endpoint `n`.
+#[cfg(not(feature = "_fixed-fifo-size"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ActiveFifos {
+    pub tx: u16,
+    pub rx: u16,
+}
+
+#[cfg(not(feature = "_fixed-fifo-size"))]
+impl ActiveFifos {
+    pub fn contains(&self, index: usize, direction: Direction) -> bool {
+        let mask = match direction {
+            Direction::In => self.tx,
+            Direction::Out => self.rx,
+        };
+        mask & (1 << index) != 0
+    }
+
+    pub fn set(&mut self, index: usize, direction: Direction, active: bool) {
+        let mask = match direction {
+            Direction::In => &mut self.tx,
+            Direction::Out => &mut self.rx,
+        };
+        if active {
+            *mask |= 1 << index;
+        } else {
+            *mask &= !(1 << index);
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (usize, Direction)> + '_ {
+        (1..ENDPOINTS.len())
+            .flat_map(|index| [(index, Direction::In), (index, Direction::Out)])
+            .filter(|&(index, direction)| self.contains(index, direction))
+    }
+}
+
+/// Largest block of FIFO RAM, in bytes, covered neither by EP0 nor by `regions`
+/// (`(addr, size)` in 8-byte units, possibly overlapping when over-committed).
+#[cfg(not(feature = "_fixed-fifo-size"))]
+fn largest_free_block(regions: impl Iterator<Item = (u16, u16)>) -> u16 {
+    let mut sorted = [(0u16, 0u16); 2 * ENDPOINTS.len()];
+    let mut len = 0;
+    for region in regions {
+        sorted[len] = region;
+        len += 1;
+    }
+    let sorted = &mut sorted[..len];
+    sorted.sort_unstable();
+
+    let mut largest = 0;
+    let mut end = EP0_FIFO_SIZE_8BYTES;
+    for &(addr, size) in sorted.iter() {
+        largest = largest.max(addr.saturating_sub(end));
+        end = end.max(addr + size);
+    }
+    largest.max(FIFO_END_8BYTES.saturating_sub(end)) * 8
+}
+
+/// Returns `(addr, size)` of the FIFO of an endpoint, in 8-byte units.
+#[cfg(not(feature = "_fixed-fifo-size"))]
+fn fifo_region(conf: &EndpointConfig, direction: Direction) -> (u16, u16) {
+    let (addr, size_bits, double_buffered) = match direction {
+        Direction::In => (conf.tx_fifo_addr_8bytes, conf.tx_fifo_size_bits, conf.tx_double_buffered),
+        Direction::Out => (conf.rx_fifo_addr_8bytes, conf.rx_fifo_size_bits, conf.rx_double_buffered),
+    };
+    let buffers = if double_buffered { 2 } else { 1 };
+    (addr, (1 << size_bits) / 8 * buffers)
+}
+
+#[cfg(not(feature = "_fixed-fifo-size"))]
+fn set_fifo_addr(conf: &mut EndpointConfig, direction: Direction, addr_8bytes: u16) {
+    match direction {
+        Direction::In => conf.tx_fifo_addr_8bytes = addr_8bytes,
+        Direction::Out => conf.rx_fifo_addr_8bytes = addr_8bytes,
+    }
+}
+
+/// Places the FIFO of an endpoint about to be enabled in the FIFO RAM not used by
+/// the `active` endpoints.
+///
+/// The allocated address is kept if it is still free, otherwise the first free
+/// block large enough is used. If the free RAM is fragmented, the active IN FIFOs
+/// are compacted around the active OUT FIFOs, which never move as the host may
+/// send a packet at any time. This requires `tx_movable` to return `true` for each
+/// active IN endpoint, i.e. its FIFO must be empty. Returns the active endpoints
+/// whose address changed and must be re-programmed.
+#[cfg(not(feature = "_fixed-fifo-size"))]
+pub fn place_fifo(
+    confs: &mut [EndpointConfig; ENDPOINTS.len()],
+    active: ActiveFifos,
+    index: usize,
+    direction: Direction,
+    tx_movable: impl Fn(usize) -> bool,
+) -> Result<ActiveFifos, EndpointAllocError> {
+    let mut others = active;
+    others.set(index, direction, false);
+
+    let (hint, size) = fifo_region(&confs[index], direction);
+    let is_free = |addr: u16| {
+        addr >= EP0_FIFO_SIZE_8BYTES
+            && addr + size <= FIFO_END_8BYTES
+            && others.iter().all(|(i, d)| {
+                let (other_addr, other_size) = fifo_region(&confs[i], d);
+                addr + size <= other_addr || other_addr + other_size <= addr
+            })
+    };
+
+    // First fit, starting with the allocated address.
+    let free_addr = core::iter::once(hint)
+        .chain(core::iter::once(EP0_FIFO_SIZE_8BYTES))
+        .chain(others.iter().map(|(i, d)| {
+            let (addr, size) = fifo_region(&confs[i], d);
+            addr + size
+        }))
+        .find(|&addr| is_free(addr));
+    if let Some(addr) = free_addr {
+        set_fifo_addr(&mut confs[index], direction, addr);
+        return Ok(ActiveFifos::default());
+    }
+
+    let used: u16 = others.iter().map(|(i, d)| fifo_region(&confs[i], d).1).sum();
+    if used + size > FIFO_END_8BYTES - EP0_FIFO_SIZE_8BYTES {
+        return Err(EndpointAllocError::BufferOverflow);
+    }
+    if !others.iter().all(|(i, d)| d == Direction::Out || tx_movable(i)) {
+        return Err(EndpointAllocError::FifoBusy);
+    }
+
+    // Compaction: the OUT FIFOs stay where they are, the IN FIFOs and the new
+    // FIFO are placed first fit by decreasing size, which leaves no gaps between
+    // power-of-two blocks when no OUT FIFO is in the way.
+    let mut placed = [(0u16, 0u16); 2 * ENDPOINTS.len()];
+    let mut placed_len = 0;
+    let mut blocks = [(0u16, 0usize, Direction::In); 2 * ENDPOINTS.len()];
+    let mut len = 0;
+    let mut all = others;
+    all.set(index, direction, true);
+    for (i, d) in all.iter() {
+        if others.contains(i, d) && d == Direction::Out {
+            placed[placed_len] = fifo_region(&confs[i], d);
+            placed_len += 1;
+        } else {
+            blocks[len] = (fifo_region(&confs[i], d).1, i, d);
+            len += 1;
+        }
+    }
+    let blocks = &mut blocks[..len];
+    blocks.sort_unstable_by_key(|&(size, _, _)| core::cmp::Reverse(size));
+
+    let mut addrs = [0u16; 2 * ENDPOINTS.len()];
+    for (n, &(size, _, _)) in blocks.iter().enumerate() {
+        let taken = &placed[..placed_len];
+        let fits = |addr: u16| {
+            addr + size <= FIFO_END_8BYTES
+                && taken
+                    .iter()
+                    .all(|&(other_addr, other_size)| addr + size <= other_addr || other_addr + other_size <= addr)
+        };
+        let addr = core::iter::once(EP0_FIFO_SIZE_8BYTES)
+            .chain(taken.iter().map(|&(addr, size)| addr + size))
+            .find(|&addr| fits(addr))
+            .ok_or(EndpointAllocError::FifoBusy)?;
+        addrs[n] = addr;
+        placed[placed_len] = (addr, size);
+        placed_len += 1;
+    }
+
+    let mut moved = ActiveFifos::default();
+    for (&(_, i, d), &addr) in blocks.iter().zip(addrs.iter()) {
+        if fifo_region(&confs[i], d).0 != addr && others.contains(i, d) {
+            moved.set(i, d, true);
+        }
+        set_fifo_addr(&mut confs[i], d, addr);
+    }
+    Ok(moved)
+}
+
+/// FIFO of one endpoint direction, see [`fifo_map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FifoMapEntry {
+    pub ep_type: EndpointType,
+    pub max_packet_size: u16,
+    /// FIFO start address in bytes, `None` on fixed FIFO cores where it is not known.
+    pub fifo_addr: Option<u16>,
+    /// FIFO size in bytes, both buffers included when double buffered.
+    pub fifo_size: u16,
+    pub double_buffered: bool,
+}
+
+/// FIFO layout of the allocated endpoints, indexed by endpoint number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FifoMap {
+    /// IN endpoints.
+    pub tx: [Option<FifoMapEntry>; ENDPOINTS.len()],
+    /// OUT endpoints.
+    pub rx: [Option<FifoMapEntry>; ENDPOINTS.len()],
+    /// FIFO RAM not allocated to any endpoint, in bytes.
+    ///
+    /// On fixed FIFO cores, this is the FIFO size of the unused endpoints.
+    pub free_bytes: u16,
+    /// Largest contiguous free block, less than `free_bytes` when the free space is fragmented.
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    pub largest_free_bytes: u16,
+}
+
+/// Describes the FIFO layout of the endpoints in `alloc`, as assigned at allocation.
+pub fn fifo_map(
+    alloc: &[EndpointData; ENDPOINTS.len()],
+    #[cfg(not(feature = "_fixed-fifo-size"))] fifo: &FifoAllocator,
+) -> FifoMap {
+    let map = map_fifos(
+        |index| &alloc[index].ep_conf,
+        |index, direction| match direction {
+            Direction::In => alloc[index].used_tx,
+            Direction::Out => alloc[index].used_rx,
+        },
+    );
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    let map = {
+        let usage = fifo.usage(alloc);
+        FifoMap {
+            free_bytes: usage.free_bytes,
+            largest_free_bytes: usage.largest_free_bytes,
+            ..map
+        }
+    };
+    map
+}
+
+/// Describes the FIFO layout of the enabled endpoints, as programmed at runtime.
+///
+/// `confs` are the endpoint configurations updated by [`place_fifo`], EP0 is
+/// always included.
+pub fn enabled_fifo_map(
+    confs: &[EndpointConfig; ENDPOINTS.len()],
+    enabled: impl Fn(usize, Direction) -> bool,
+) -> FifoMap {
+    let map = map_fifos(|index| &confs[index], |index, direction| index == 0 || enabled(index, direction));
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    let map = {
+        let mut used_bytes = EP0_FIFO_SIZE_8BYTES * 8;
+        for index in 1..ENDPOINTS.len() {
+            for entry in [map.tx[index], map.rx[index]].into_iter().flatten() {
+                used_bytes += entry.fifo_size;
+            }
+        }
+        let regions = (1..ENDPOINTS.len())
+            .flat_map(|index| [(index, Direction::In), (index, Direction::Out)])
+            .filter(|&(index, direction)| enabled(index, direction))
+            .map(|(index, direction)| fifo_region(&confs[index], direction));
+        FifoMap {
+            free_bytes: TOTAL_FIFO_SIZE.saturating_sub(used_bytes),
+            largest_free_bytes: largest_free_block(regions),
+            ..map
+        }
+    };
+    map
+}
+
+/// Builds the map entries of the endpoint directions for which `used` is true.
+///
+/// On fixed FIFO cores, `free_bytes` is filled in as well.
+fn map_fifos<'a>(
+    conf: impl Fn(usize) -> &'a EndpointConfig,
+    used: impl Fn(usize, Direction) -> bool,
+) -> FifoMap {
+    let mut map = FifoMap {
+        tx: [None; ENDPOINTS.len()],
+        rx: [None; ENDPOINTS.len()],
+        free_bytes: 0,
+        #[cfg(not(feature = "_fixed-fifo-size"))]
+        largest_free_bytes: 0,
+    };
+
+    for (index, info) in ENDPOINTS.iter().enumerate() {
+        let conf = conf(index);
+        for direction in [Direction::In, Direction::Out] {
+            let (max_packet_size, double_buffered) = match direction {
+                Direction::In => (conf.tx_max_packet_size, conf.tx_double_buffered),
+                Direction::Out => (conf.rx_max_packet_size, conf.rx_double_buffered),
+            };
+            if !used(index, direction) {
+                continue;
+            }
+
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "_fixed-fifo-size")] {
+                    let (fifo_addr, fifo_size) = (None, info.max_packet_size);
+                } else {
+                    let _ = info;
+                    let (fifo_addr, fifo_size) = if index == 0 {
+                        (0, EP0_FIFO_SIZE_8BYTES)
+                    } else {
+                        fifo_region(conf, direction)
+                    };
+                    let (fifo_addr, fifo_size) = (Some(fifo_addr * 8), fifo_size * 8);
+                }
+            }
+
+            let entry = Some(FifoMapEntry {
+                ep_type: conf.ep_type,
+                max_packet_size,
+                fifo_addr,
+                fifo_size,
+                double_buffered,
+            });
+            match direction {
+                Direction::In => map.tx[index] = entry,
+                Direction::Out => map.rx[index] = entry,
+            }
+        }
+    }
+
+    #[cfg(feature = "_fixed-fifo-size")]
+    for (index, info) in ENDPOINTS.iter().enumerate() {
+        let size = info.max_packet_size;
+        let ep_direction = info.ep_direction;
+        let used_tx = used(index, Direction::In);
+        let used_rx = used(index, Direction::Out);
+        if cfg!(feature = "_ep-shared-fifo") || ep_direction != crate::info::EpDirection::RXTX {
+            if !used_tx && !used_rx {
+                map.free_bytes += size;
+            }
+        } else {
+            map.free_bytes += size * (!used_tx as u16 + !used_rx as u16);
+        }
+    }
+
+    map
+}
+
+/// An endpoint of an [`EndpointPlan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PlannedEndpoint {
+    /// Endpoint address, direction bit included (e.g. `0x81` for EP1 IN).
+    pub addr: u8,
+    pub ep_type: EndpointType,
+    pub max_packet_size: u16,
+    pub double_buffered: bool,
+    /// Interface and alternate setting the endpoint belongs to, if it is only used
+    /// in one alternate setting.
+    pub alt_setting: Option<(u8, u8)>,
+}
+
+impl PlannedEndpoint {
+    pub const fn new(addr: u8, ep_type: EndpointType, max_packet_size: u16) -> Self {
+        Self {
+            addr,
+            ep_type,
+            max_packet_size,
+            double_buffered: false,
+            alt_setting: None,
+        }
+    }
+
+    /// Requests double packet buffering.
+    pub const fn double_buffered(mut self) -> Self {
+        self.double_buffered = true;
+        self
+    }
+
+    /// Marks the endpoint as only used in alternate setting `alt` of `interface`.
+    ///
+    /// Endpoints of different alternate settings of the same interface are never
+    /// enabled together, so they may share a FIFO.
+    pub const fn alt_setting(mut self, interface: u8, alt: u8) -> Self {
+        self.alt_setting = Some((interface, alt));
+        self
+    }
+
+    /// Whether the two endpoints can never be enabled at the same time.
+    const fn is_exclusive_with(&self, other: &PlannedEndpoint) -> bool {
+        match (self.alt_setting, other.alt_setting) {
+            (Some((interface, alt)), Some((other_interface, other_alt))) => {
+                interface == other_interface && alt != other_alt
+            }
+            _ => false,
+        }
+    }
+
+    pub const fn index(&self) -> usize {
+        (self.addr & 0x0f) as usize
+    }
+
+    pub const fn direction(&self) -> Direction {
+        if self.addr & 0x80 != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+
+    /// FIFO RAM needed by the endpoint, in bytes.
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    const fn fifo_size(&self) -> u16 {
+        let size = self.max_packet_size.next_power_of_two();
+        let size = if size < 8 { 8 } else { size };
+        if self.double_buffered {
+            size * 2
+        } else {
+            size
+        }
+    }
+}
+
+/// Endpoint layout checked at compile time.
+///
+/// Build it in a `const` (e.g. `const PLAN: EndpointPlan<2> = EndpointPlan::new([...]);`)
+/// so that an invalid layout fails the build instead of an endpoint allocation at
+/// runtime.
+///
+/// The RAM check assumes one packet per FIFO load, i.e. no bulk multiplier.
+///
+/// ```
+/// use embassy_usb_driver::EndpointType;
+/// use musb::alloc_endpoint::{EndpointPlan, PlannedEndpoint};
+///
+/// const PLAN: EndpointPlan<2> = EndpointPlan::new([
+///     PlannedEndpoint::new(0x81, EndpointType::Bulk, 64),
+///     PlannedEndpoint::new(0x02, EndpointType::Bulk, 64),
+/// ]);
+/// ```
+///
+/// Endpoint 0 belongs to the control pipe:
+///
+/// ```compile_fail
+/// use embassy_usb_driver::EndpointType;
+/// use musb::alloc_endpoint::{EndpointPlan, PlannedEndpoint};
+///
+/// const PLAN: EndpointPlan<1> = EndpointPlan::new([PlannedEndpoint::new(0x80, EndpointType::Bulk, 64)]);
+/// ```
+///
+/// An address can only be planned once:
+///
+/// ```compile_fail
+/// use embassy_usb_driver::EndpointType;
+/// use musb::alloc_endpoint::{EndpointPlan, PlannedEndpoint};
+///
+/// const PLAN: EndpointPlan<2> = EndpointPlan::new([
+///     PlannedEndpoint::new(0x81, EndpointType::Bulk, 64),
+///     PlannedEndpoint::new(0x81, EndpointType::Bulk, 64),
+/// ]);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct EndpointPlan<const N: usize> {
+    endpoints: [PlannedEndpoint; N],
+}
+
+impl<const N: usize> EndpointPlan<N> {
+    /// Validates `endpoints`, panics (at compile time in a `const`) if they do not fit.
+    pub const fn new(endpoints: [PlannedEndpoint; N]) -> Self {
+        #[cfg(not(feature = "_fixed-fifo-size"))]
+        let mut fifo_bytes: u32 = EP0_FIFO_SIZE_8BYTES as u32 * 8;
+
+        let mut i = 0;
+        while i < N {
+            let ep = &endpoints[i];
+            let index = ep.index();
+
+            if ep.addr & 0x70 != 0 || index >= ENDPOINTS.len() {
+                panic!("endpoint plan: invalid endpoint address");
+            }
+            if index == 0 || matches!(ep.ep_type, EndpointType::Control) {
+                panic!("endpoint plan: endpoint 0 is reserved for the control pipe");
+            }
+            let supported = match ENDPOINTS[index].ep_direction {
+                EpDirection::RXTX => true,
+                EpDirection::TX => matches!(ep.direction(), Direction::In),
+                EpDirection::RX => matches!(ep.direction(), Direction::Out),
+            };
+            if !supported {
+                panic!("endpoint plan: direction not supported by this endpoint");
+            }
+            if ep.max_packet_size > ENDPOINTS[index].max_packet_size {
+                panic!("endpoint plan: max packet size bigger than the endpoint FIFO");
+            }
+            if ep.double_buffered
+                && (cfg!(feature = "_lite") || ep.max_packet_size * 2 > ENDPOINTS[index].max_packet_size)
+            {
+                panic!("endpoint plan: endpoint FIFO too small for double buffering");
+            }
+
+            let mut j = 0;
+            while j < i {
+                let other = &endpoints[j];
+                if other.index() == index {
+                    if other.addr == ep.addr {
+                        panic!("endpoint plan: endpoint address used twice");
+                    }
+                    if cfg!(feature = "_ep-shared-fifo") && !ep.is_exclusive_with(other) {
+                        panic!("endpoint plan: shared FIFO endpoints can only be used in one direction per alternate setting");
+                    }
+                    if !same_type(ep.ep_type, other.ep_type) {
+                        panic!("endpoint plan: IN and OUT endpoints of the same number must have the same type");
+                    }
+                }
+                j += 1;
+            }
+
+            #[cfg(not(feature = "_fixed-fifo-size"))]
+            {
+                fifo_bytes += ep.fifo_size() as u32;
+            }
+            i += 1;
+        }
+
+        #[cfg(not(feature = "_fixed-fifo-size"))]
+        if fifo_bytes > TOTAL_FIFO_SIZE as u32 {
+            panic!("endpoint plan: endpoints need more FIFO RAM than available");
+        }
+
+        Self { endpoints }
+    }
+
+    pub const fn endpoints(&self) -> &[PlannedEndpoint] {
+        &self.endpoints
+    }
+}
+
+const fn same_type(a: EndpointType, b: EndpointType) -> bool {
+    a as u8 == b as u8
 }
 
 pub fn alloc_endpoint(
     alloc: &mut [EndpointData; ENDPOINTS.len()],
-    #[cfg(not(feature = "_fixed-fifo-size"))] next_fifo_addr_8bytes: &mut u16,
+    #[cfg(not(feature = "_fixed-fifo-size"))] fifo: &mut FifoAllocator,
     ep_type: EndpointType,
     ep_index: Option<u8>,
     direction: Direction,
     max_packet_size: u16,
+    options: AllocOptions,
 ) -> Result<u8, EndpointAllocError> {
+    if ep_type == EndpointType::Control && !is_valid_control_max_packet_size(max_packet_size) {
+        return Err(EndpointAllocError::InvalidControlMaxPacketSize);
+    }
+    if max_packet_size > crate::common_impl::max_packet_size_limit(ep_type, options.high_speed) {
+        return Err(EndpointAllocError::MaxPacketSizeExceedsSpeedLimit);
+    }
+
     let res = if let Some(index) = ep_index {
         if index >= ENDPOINTS.len() as u8 {
             return Err(EndpointAllocError::InvalidEndpoint);
@@ -53,6 +771,10 @@ pub fn alloc_endpoint(
         if index == 0 {
             Some((0, &mut alloc[0]))
         } else {
+            #[cfg(feature = "_ep-shared-fifo")]
+            if !options.direction_switching && (alloc[index as usize].used_tx || alloc[index as usize].used_rx) {
+                return Err(EndpointAllocError::EpUsed);
+            }
             check_endpoint(
                 &alloc[index as usize],
                 ep_type,
@@ -67,6 +789,10 @@ pub fn alloc_endpoint(
             if *i == 0 {
                 return false; // reserved for control pipe
             }
+            #[cfg(feature = "_ep-shared-fifo")]
+            if ep.used_tx || ep.used_rx {
+                return false;
+            }
             check_endpoint(ep, ep_type, *i as _, direction, max_packet_size).is_ok()
         })
     };
@@ -76,54 +802,73 @@ pub fn alloc_endpoint(
         None => return Err(EndpointAllocError::EndpointOverflow),
     };
 
-    ep.ep_conf.ep_type = ep_type;
-    
+    // Double buffering is only granted if the endpoint FIFO can hold two packets,
+    // lite cores have no way to control it.
+    let double_buffered = options.double_buffered_endpoints & (1 << index) != 0
+        && cfg!(not(feature = "_lite"))
+        && ep_type != EndpointType::Control
+        && max_packet_size * 2 <= ENDPOINTS[index].max_packet_size;
+    let buffers = if double_buffered { 2 } else { 1 };
+
+    // Use as many packets per FIFO load as the endpoint FIFO can hold.
+    let packet_multiplier = ENDPOINTS[index]
+        .max_packet_size
+        .checked_div(max_packet_size * buffers)
+        .unwrap_or(1)
+        .clamp(1, options.max_packet_multiplier.max(1) as u16) as u8;
+
     // --- Dynamic FIFO Allocation Logic ---
+    // Reserve the FIFO RAM first, so that a failing allocation leaves `ep` untouched.
     #[cfg(not(feature = "_fixed-fifo-size"))]
-    if ep_type == EndpointType::Control {
-        assert!(max_packet_size <= 64, "endpoint0 max packet size must be <= 64");
-        match direction {
-            // EP0 has fixed FIFO size(64k) and address.
-            Direction::Out => {
-                ep.ep_conf.rx_max_packet_size = max_packet_size;
-                ep.ep_conf.rx_fifo_size_bits = 0;
-                ep.ep_conf.rx_fifo_addr_8bytes = 0;
-            }
-            Direction::In => {
-                ep.ep_conf.tx_max_packet_size = max_packet_size;
-                ep.ep_conf.tx_fifo_size_bits =0;
-                ep.ep_conf.tx_fifo_addr_8bytes = 0;
+    let (fifo_size_bits, fifo_addr_8bytes) = if ep_type == EndpointType::Control {
+        // EP0 has fixed FIFO size(64k) and address.
+        (0, 0)
+    } else {
+        let fifo_size_bytes = (max_packet_size * packet_multiplier as u16)
+            .next_power_of_two()
+            .max(8);
+        // FIFO_SZ describes a single buffer, DPB uses twice that space.
+        let size_8bytes = fifo_size_bytes / 8 * buffers;
+        let addr = match fifo.alloc(direction, size_8bytes) {
+            Some(addr) => addr,
+            // The address is only a hint, `place_fifo` moves it when enabled.
+            None if options.overcommit_fifo && size_8bytes <= FIFO_END_8BYTES - EP0_FIFO_SIZE_8BYTES => {
+                match direction {
+                    Direction::In => EP0_FIFO_SIZE_8BYTES,
+                    Direction::Out => FIFO_END_8BYTES - size_8bytes,
+                }
             }
+            None => return Err(EndpointAllocError::BufferOverflow),
+        };
+        (fifo_size_bytes.trailing_zeros() as u8, addr)
+    };
+
+    ep.ep_conf.ep_type = ep_type;
+    match direction {
+        Direction::Out => {
+            ep.ep_conf.rx_packet_multiplier = packet_multiplier;
+            ep.ep_conf.rx_double_buffered = double_buffered;
         }
-    }
-    else {
-        let fifo_size_bytes = max_packet_size.next_power_of_two().max(8) as u16;
-        let fifo_size_8bytes = fifo_size_bytes / 8;
-        
-        let assigned_addr_8bytes = *next_fifo_addr_8bytes;
-        
-        if ep.ep_conf.ep_type == EndpointType::Control {
-            *next_fifo_addr_8bytes += fifo_size_8bytes;
+        Direction::In => {
+            ep.ep_conf.tx_packet_multiplier = packet_multiplier;
+            ep.ep_conf.tx_double_buffered = double_buffered;
         }
+    }
 
-        if *next_fifo_addr_8bytes * 8 > TOTAL_FIFO_SIZE {
-            return Err(EndpointAllocError::BufferOverflow);
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    match direction {
+        Direction::Out => {
+            ep.ep_conf.rx_max_packet_size = max_packet_size;
+            ep.ep_conf.rx_fifo_size_bits = fifo_size_bits;
+            ep.ep_conf.rx_fifo_addr_8bytes = fifo_addr_8bytes;
         }
-
-        match direction {
-            Direction::Out => {
-                ep.ep_conf.rx_max_packet_size = max_packet_size;
-                ep.ep_conf.rx_fifo_size_bits = fifo_size_bytes.trailing_zeros() as u8;
-                ep.ep_conf.rx_fifo_addr_8bytes = assigned_addr_8bytes;
-            }
-            Direction::In => {
-                ep.ep_conf.tx_max_packet_size = max_packet_size;
-                ep.ep_conf.tx_fifo_size_bits = fifo_size_bytes.trailing_zeros() as u8;
-                ep.ep_conf.tx_fifo_addr_8bytes = assigned_addr_8bytes;
-            }
+        Direction::In => {
+            ep.ep_conf.tx_max_packet_size = max_packet_size;
+            ep.ep_conf.tx_fifo_size_bits = fifo_size_bits;
+            ep.ep_conf.tx_fifo_addr_8bytes = fifo_addr_8bytes;
         }
     }
-    
+
     #[cfg(feature = "_fixed-fifo-size")]
     {
         // For fixed FIFO, we don't calculate or assign, just record the packet size.
@@ -142,6 +887,11 @@ pub fn alloc_endpoint(
     Ok(index as u8)
 }
 
+/// Whether `max_packet_size` is allowed for the control endpoint (USB 2.0 5.5.3).
+pub const fn is_valid_control_max_packet_size(max_packet_size: u16) -> bool {
+    matches!(max_packet_size, 8 | 16 | 32 | 64)
+}
+
 fn check_endpoint(
     ep: &EndpointData,
     alloc_ep_type: EndpointType,
@@ -150,10 +900,6 @@ fn check_endpoint(
     max_packet_size: u16,
 ) -> Result<(), EndpointAllocError> {
     let used = ep.used_rx || ep.used_tx;
-    let _ = index;
-
-    #[cfg(feature = "_ep-shared-fifo")]
-    if used && index != 0 { return Err(EndpointAllocError::EpUsed) }
 
     if max_packet_size > ENDPOINTS[index as usize].max_packet_size {
         return Err(EndpointAllocError::MaxPacketSizeBiggerThanEpFifoSize);
@@ -174,10 +920,6 @@ fn check_endpoint(
         }
     }
 
-    if alloc_ep_type == EndpointType::Bulk && used {
-        return Err(EndpointAllocError::EpUsed);
-    }
-
     let used_dir = match direction {
         Direction::Out => ep.used_rx,
         Direction::In => ep.used_tx,
@@ -190,3 +932,896 @@ fn check_endpoint(
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_alloc() -> [EndpointData; ENDPOINTS.len()] {
+        [EndpointData {
+            ep_conf: EndpointConfig {
+                ep_type: EndpointType::Bulk,
+                tx_max_packet_size: 0,
+                rx_max_packet_size: 0,
+                tx_packet_multiplier: 1,
+                rx_packet_multiplier: 1,
+                tx_auto_set: false,
+                rx_auto_clear: false,
+                tx_double_buffered: false,
+                rx_double_buffered: false,
+                #[cfg(not(feature = "_fixed-fifo-size"))]
+                tx_fifo_size_bits: 0,
+                #[cfg(not(feature = "_fixed-fifo-size"))]
+                rx_fifo_size_bits: 0,
+                #[cfg(not(feature = "_fixed-fifo-size"))]
+                tx_fifo_addr_8bytes: 0,
+                #[cfg(not(feature = "_fixed-fifo-size"))]
+                rx_fifo_addr_8bytes: 0,
+            },
+            used_tx: false,
+            used_rx: false,
+        }; ENDPOINTS.len()]
+    }
+
+    #[test]
+    fn invalid_endpoint_index() {
+        let mut alloc = empty_alloc();
+        #[cfg(not(feature = "_fixed-fifo-size"))]
+        let mut fifo = FifoAllocator::new();
+        let res = alloc_endpoint(
+            &mut alloc,
+            #[cfg(not(feature = "_fixed-fifo-size"))]
+            &mut fifo,
+            EndpointType::Interrupt,
+            Some(ENDPOINTS.len() as u8),
+            Direction::In,
+            8,
+            AllocOptions::default(),
+        );
+        assert_eq!(res, Err(EndpointAllocError::InvalidEndpoint));
+    }
+
+    #[test]
+    fn control_max_packet_size_is_validated() {
+        for max_packet_size in 0..=128u16 {
+            let mut alloc = empty_alloc();
+            #[cfg(not(feature = "_fixed-fifo-size"))]
+            let mut fifo = FifoAllocator::new();
+            let res = alloc_endpoint(
+                &mut alloc,
+                #[cfg(not(feature = "_fixed-fifo-size"))]
+                &mut fifo,
+                EndpointType::Control,
+                Some(0),
+                Direction::In,
+                max_packet_size,
+                AllocOptions::default(),
+            );
+            if [8, 16, 32, 64].contains(&max_packet_size) {
+                assert_eq!(res, Ok(0));
+                assert_eq!(alloc[0].ep_conf.tx_max_packet_size, max_packet_size);
+            } else {
+                assert_eq!(res, Err(EndpointAllocError::InvalidControlMaxPacketSize));
+                assert!(!alloc[0].used_tx);
+            }
+        }
+    }
+
+    #[test]
+    fn max_packet_size_speed_limits() {
+        let index = (1..ENDPOINTS.len())
+            .find(|&i| ENDPOINTS[i].ep_direction != EpDirection::RX && ENDPOINTS[i].max_packet_size >= 128)
+            .map(|i| i as u8);
+        for (ep_type, full_speed, high_speed) in [
+            (EndpointType::Bulk, 64, 512),
+            (EndpointType::Interrupt, 64, 1024),
+            (EndpointType::Isochronous, 1023, 1024),
+        ] {
+            for (high_speed_allowed, limit) in [(false, full_speed), (true, high_speed)] {
+                let mut alloc = empty_alloc();
+                #[cfg(not(feature = "_fixed-fifo-size"))]
+                let mut fifo = FifoAllocator::new();
+                let res = alloc_endpoint(
+                    &mut alloc,
+                    #[cfg(not(feature = "_fixed-fifo-size"))]
+                    &mut fifo,
+                    ep_type,
+                    index,
+                    Direction::In,
+                    limit + 1,
+                    AllocOptions {
+                        high_speed: high_speed_allowed,
+                        ..AllocOptions::default()
+                    },
+                );
+                assert_eq!(res, Err(EndpointAllocError::MaxPacketSizeExceedsSpeedLimit));
+            }
+        }
+    }
+
+    #[test]
+    fn control_endpoint_uses_index_zero() {
+        let mut alloc = empty_alloc();
+        #[cfg(not(feature = "_fixed-fifo-size"))]
+        let mut fifo = FifoAllocator::new();
+        for direction in [Direction::Out, Direction::In] {
+            let res = alloc_endpoint(
+                &mut alloc,
+                #[cfg(not(feature = "_fixed-fifo-size"))]
+                &mut fifo,
+                EndpointType::Control,
+                Some(0),
+                direction,
+                64,
+                AllocOptions::default(),
+            );
+            assert_eq!(res, Ok(0));
+        }
+        assert!(alloc[0].used_tx && alloc[0].used_rx);
+    }
+
+    #[test]
+    fn endpoint_plan_accepts_valid_layout() {
+        let endpoints: [PlannedEndpoint; 2] = core::array::from_fn(|n| {
+            let index = n + 1;
+            let addr = match ENDPOINTS[index].ep_direction {
+                EpDirection::RX => index as u8,
+                _ => 0x80 | index as u8,
+            };
+            PlannedEndpoint::new(addr, EndpointType::Interrupt, 8)
+        });
+        let plan = EndpointPlan::new(endpoints);
+        assert_eq!(plan.endpoints(), &endpoints);
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved for the control pipe")]
+    fn endpoint_plan_rejects_ep0() {
+        EndpointPlan::new([PlannedEndpoint::new(0x80, EndpointType::Interrupt, 8)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid endpoint address")]
+    fn endpoint_plan_rejects_invalid_index() {
+        EndpointPlan::new([PlannedEndpoint::new(0x80 | ENDPOINTS.len() as u8, EndpointType::Interrupt, 8)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "bigger than the endpoint FIFO")]
+    fn endpoint_plan_rejects_max_packet_size() {
+        let index = (1..ENDPOINTS.len()).find(|&i| ENDPOINTS[i].ep_direction != EpDirection::RX).unwrap();
+        EndpointPlan::new([PlannedEndpoint::new(
+            0x80 | index as u8,
+            EndpointType::Interrupt,
+            ENDPOINTS[index].max_packet_size + 1,
+        )]);
+    }
+
+    #[cfg(feature = "_ep-shared-fifo")]
+    #[test]
+    fn endpoint_plan_shares_fifo_between_alt_settings() {
+        let index = (1..ENDPOINTS.len()).find(|&i| ENDPOINTS[i].ep_direction == EpDirection::RXTX).unwrap() as u8;
+        EndpointPlan::new([
+            PlannedEndpoint::new(0x80 | index, EndpointType::Bulk, 8).alt_setting(1, 0),
+            PlannedEndpoint::new(index, EndpointType::Bulk, 8).alt_setting(1, 1),
+        ]);
+    }
+
+    #[cfg(feature = "_ep-shared-fifo")]
+    #[test]
+    #[should_panic(expected = "one direction per alternate setting")]
+    fn endpoint_plan_rejects_shared_fifo_in_one_alt_setting() {
+        let index = (1..ENDPOINTS.len()).find(|&i| ENDPOINTS[i].ep_direction == EpDirection::RXTX).unwrap() as u8;
+        EndpointPlan::new([
+            PlannedEndpoint::new(0x80 | index, EndpointType::Bulk, 8).alt_setting(1, 0),
+            PlannedEndpoint::new(index, EndpointType::Bulk, 8).alt_setting(2, 1),
+        ]);
+    }
+
+    #[cfg(feature = "_ep-shared-fifo")]
+    #[test]
+    fn shared_fifo_direction_switching() {
+        let alloc_with = |alloc: &mut [EndpointData; ENDPOINTS.len()], index, direction, direction_switching| {
+            alloc_endpoint(
+                alloc,
+                EndpointType::Bulk,
+                index,
+                direction,
+                8,
+                AllocOptions {
+                    direction_switching,
+                    ..AllocOptions::default()
+                },
+            )
+        };
+
+        let mut alloc = empty_alloc();
+        assert_eq!(alloc_with(&mut alloc, Some(1), Direction::In, false), Ok(1));
+        assert_eq!(alloc_with(&mut alloc, Some(1), Direction::Out, false), Err(EndpointAllocError::EpUsed));
+        assert_eq!(alloc_with(&mut alloc, Some(1), Direction::Out, true), Ok(1));
+        assert_eq!(alloc_with(&mut alloc, Some(1), Direction::Out, true), Err(EndpointAllocError::EpUsed));
+
+        // Automatic allocation never shares an endpoint.
+        let mut alloc = empty_alloc();
+        assert_eq!(alloc_with(&mut alloc, Some(1), Direction::In, true), Ok(1));
+        assert_eq!(alloc_with(&mut alloc, None, Direction::Out, true), Ok(2));
+    }
+
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    mod dynamic_fifo {
+        use super::*;
+
+        /// FIFO sizing is tested up to the high-speed max packet sizes.
+        fn high_speed_options() -> AllocOptions {
+            AllocOptions {
+                high_speed: true,
+                ..AllocOptions::default()
+            }
+        }
+
+        struct Harness {
+            alloc: [EndpointData; ENDPOINTS.len()],
+            fifo: FifoAllocator,
+        }
+
+        impl Harness {
+            fn new() -> Self {
+                let mut h = Self {
+                    alloc: empty_alloc(),
+                    fifo: FifoAllocator::new(),
+                };
+                h.alloc(EndpointType::Control, Some(0), Direction::Out, 64)
+                    .unwrap();
+                h.alloc(EndpointType::Control, Some(0), Direction::In, 64)
+                    .unwrap();
+                h
+            }
+
+            fn alloc(
+                &mut self,
+                ep_type: EndpointType,
+                index: Option<u8>,
+                direction: Direction,
+                max_packet_size: u16,
+            ) -> Result<u8, EndpointAllocError> {
+                self.alloc_with(
+                    ep_type,
+                    index,
+                    direction,
+                    max_packet_size,
+                    high_speed_options(),
+                )
+            }
+
+            fn alloc_with(
+                &mut self,
+                ep_type: EndpointType,
+                index: Option<u8>,
+                direction: Direction,
+                max_packet_size: u16,
+                options: AllocOptions,
+            ) -> Result<u8, EndpointAllocError> {
+                alloc_endpoint(
+                    &mut self.alloc,
+                    &mut self.fifo,
+                    ep_type,
+                    index,
+                    direction,
+                    max_packet_size,
+                    options,
+                )
+            }
+
+            /// Returns `(addr, size)` of the FIFO of `index` in `direction`, in bytes.
+            fn region(&self, index: usize, direction: Direction) -> (u16, u16) {
+                let conf = &self.alloc[index].ep_conf;
+                let (addr, bits, double_buffered) = match direction {
+                    Direction::In => (
+                        conf.tx_fifo_addr_8bytes,
+                        conf.tx_fifo_size_bits,
+                        conf.tx_double_buffered,
+                    ),
+                    Direction::Out => (
+                        conf.rx_fifo_addr_8bytes,
+                        conf.rx_fifo_size_bits,
+                        conf.rx_double_buffered,
+                    ),
+                };
+                let buffers = if double_buffered { 2 } else { 1 };
+                (addr * 8, (1 << bits) * buffers)
+            }
+
+            fn confs(&self) -> [EndpointConfig; ENDPOINTS.len()] {
+                core::array::from_fn(|i| self.alloc[i].ep_conf)
+            }
+
+            /// Checks that all FIFOs lie in the FIFO RAM after EP0 and do not overlap.
+            fn assert_consistent(&self) {
+                let mut regions = [(0u16, 0u16); 2 * ENDPOINTS.len()];
+                let mut n = 0;
+                for index in 1..ENDPOINTS.len() {
+                    for (used, direction) in [
+                        (self.alloc[index].used_tx, Direction::In),
+                        (self.alloc[index].used_rx, Direction::Out),
+                    ] {
+                        if used {
+                            regions[n] = self.region(index, direction);
+                            n += 1;
+                        }
+                    }
+                }
+                let regions = &regions[..n];
+                for (i, &(addr, size)) in regions.iter().enumerate() {
+                    assert!(addr >= 64, "FIFO overlaps EP0: {:?}", (addr, size));
+                    assert!(
+                        addr + size <= TOTAL_FIFO_SIZE,
+                        "FIFO out of RAM: {:?}",
+                        (addr, size)
+                    );
+                    for &(other_addr, other_size) in &regions[i + 1..] {
+                        assert!(
+                            addr + size <= other_addr || other_addr + other_size <= addr,
+                            "FIFOs overlap: {:?} {:?}",
+                            (addr, size),
+                            (other_addr, other_size)
+                        );
+                    }
+                }
+                let usage = self.fifo.usage(&self.alloc);
+                let used: u16 = regions.iter().map(|&(_, size)| size).sum();
+                assert_eq!(usage.used_bytes, 64 + used);
+                assert_eq!(usage.used_bytes + usage.free_bytes, TOTAL_FIFO_SIZE);
+                assert!(usage.largest_free_bytes <= usage.free_bytes);
+            }
+        }
+
+        #[test]
+        fn ep0_reserves_first_64_bytes() {
+            let h = Harness::new();
+            let usage = h.fifo.usage(&h.alloc);
+            assert_eq!(
+                usage,
+                FifoUsage {
+                    total_bytes: TOTAL_FIFO_SIZE,
+                    used_bytes: 64,
+                    wasted_bytes: 0,
+                    free_bytes: TOTAL_FIFO_SIZE - 64,
+                    largest_free_bytes: TOTAL_FIFO_SIZE - 64,
+                }
+            );
+        }
+
+        #[test]
+        fn tx_packs_upwards_after_ep0() {
+            let mut h = Harness::new();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::In, 64)
+                .unwrap();
+            h.alloc(EndpointType::Interrupt, Some(2), Direction::In, 16)
+                .unwrap();
+            h.alloc(EndpointType::Interrupt, Some(3), Direction::In, 512)
+                .unwrap();
+            assert_eq!(h.region(1, Direction::In), (64, 64));
+            assert_eq!(h.region(2, Direction::In), (128, 16));
+            assert_eq!(h.region(3, Direction::In), (144, 512));
+            h.assert_consistent();
+        }
+
+        #[test]
+        fn rx_packs_downwards_from_end() {
+            let mut h = Harness::new();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::Out, 64)
+                .unwrap();
+            h.alloc(EndpointType::Interrupt, Some(2), Direction::Out, 512)
+                .unwrap();
+            assert_eq!(h.region(1, Direction::Out), (TOTAL_FIFO_SIZE - 64, 64));
+            assert_eq!(
+                h.region(2, Direction::Out),
+                (TOTAL_FIFO_SIZE - 64 - 512, 512)
+            );
+            h.assert_consistent();
+        }
+
+        #[test]
+        fn same_index_in_and_out_get_separate_regions() {
+            let mut h = Harness::new();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::In, 64)
+                .unwrap();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::Out, 64)
+                .unwrap();
+            assert_eq!(h.region(1, Direction::In), (64, 64));
+            assert_eq!(h.region(1, Direction::Out), (TOTAL_FIFO_SIZE - 64, 64));
+            h.assert_consistent();
+        }
+
+        #[test]
+        fn sizes_round_up_to_power_of_two() {
+            for max_packet_size in 0..=1024u16 {
+                let mut h = Harness::new();
+                h.alloc(
+                    EndpointType::Isochronous,
+                    Some(1),
+                    Direction::In,
+                    max_packet_size,
+                )
+                .unwrap();
+                let (addr, size) = h.region(1, Direction::In);
+                assert_eq!(addr, 64);
+                assert!(size.is_power_of_two());
+                assert!(size >= 8 && size >= max_packet_size);
+                assert!(size == 8 || size / 2 < max_packet_size);
+                assert_eq!(h.fifo.usage(&h.alloc).wasted_bytes, size - max_packet_size);
+                h.assert_consistent();
+            }
+        }
+
+        #[test]
+        fn double_buffering_reserves_twice_the_space() {
+            let mut h = Harness::new();
+            let options = AllocOptions {
+                double_buffered_endpoints: 1 << 1,
+                ..high_speed_options()
+            };
+            h.alloc_with(EndpointType::Bulk, Some(1), Direction::In, 64, options)
+                .unwrap();
+            h.alloc_with(EndpointType::Bulk, Some(2), Direction::In, 64, options)
+                .unwrap();
+            assert!(h.alloc[1].ep_conf.tx_double_buffered);
+            assert!(!h.alloc[2].ep_conf.tx_double_buffered);
+            assert_eq!(h.alloc[1].ep_conf.tx_fifo_size_bits, 6);
+            assert_eq!(h.region(1, Direction::In), (64, 128));
+            assert_eq!(h.region(2, Direction::In), (192, 64));
+            h.assert_consistent();
+        }
+
+        #[test]
+        fn packet_multiplier_grows_fifo() {
+            let mut h = Harness::new();
+            let options = AllocOptions {
+                max_packet_multiplier: 4,
+                ..high_speed_options()
+            };
+            h.alloc_with(EndpointType::Bulk, Some(1), Direction::Out, 64, options)
+                .unwrap();
+            assert_eq!(h.alloc[1].ep_conf.rx_packet_multiplier, 4);
+            assert_eq!(h.region(1, Direction::Out), (TOTAL_FIFO_SIZE - 256, 256));
+            h.assert_consistent();
+        }
+
+        #[test]
+        fn fills_ram_exactly() {
+            let mut h = Harness::new();
+            // 64 (EP0) + 1024 + 512 + 256 + 128 + 64 = 2048
+            let sizes = [1024, 512, 256, 128, 64];
+            for (i, size) in sizes.into_iter().enumerate() {
+                let direction = if i % 2 == 0 {
+                    Direction::In
+                } else {
+                    Direction::Out
+                };
+                h.alloc(
+                    EndpointType::Isochronous,
+                    Some(i as u8 + 1),
+                    direction,
+                    size,
+                )
+                .unwrap();
+            }
+            let usage = h.fifo.usage(&h.alloc);
+            assert_eq!(usage.free_bytes, 0);
+            assert_eq!(usage.used_bytes, TOTAL_FIFO_SIZE);
+            h.assert_consistent();
+
+            let res = h.alloc(EndpointType::Isochronous, Some(6), Direction::In, 0);
+            assert_eq!(res, Err(EndpointAllocError::BufferOverflow));
+        }
+
+        #[test]
+        fn overflow_leaves_state_untouched() {
+            let mut h = Harness::new();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::In, 1024)
+                .unwrap();
+            let before = h.fifo.usage(&h.alloc);
+
+            let options = AllocOptions {
+                double_buffered_endpoints: 1 << 2,
+                ..high_speed_options()
+            };
+            // 1024 fits once more but not double buffered
+            let res = h.alloc_with(
+                EndpointType::Interrupt,
+                Some(2),
+                Direction::Out,
+                1024,
+                options,
+            );
+            assert_eq!(res, Err(EndpointAllocError::BufferOverflow));
+            assert!(!h.alloc[2].used_rx);
+            assert!(!h.alloc[2].ep_conf.rx_double_buffered);
+            assert_eq!(h.fifo.usage(&h.alloc), before);
+
+            h.alloc(EndpointType::Interrupt, Some(2), Direction::Out, 512)
+                .unwrap();
+            h.assert_consistent();
+        }
+
+        #[test]
+        fn max_packet_size_bigger_than_fifo() {
+            let mut h = Harness::new();
+            let max_packet_size = ENDPOINTS[1].max_packet_size + 1;
+            let res = h.alloc(EndpointType::Isochronous, Some(1), Direction::In, max_packet_size);
+            // The USB limit may be the lower one.
+            let expected = if max_packet_size > 1024 {
+                EndpointAllocError::MaxPacketSizeExceedsSpeedLimit
+            } else {
+                EndpointAllocError::MaxPacketSizeBiggerThanEpFifoSize
+            };
+            assert_eq!(res, Err(expected));
+            h.assert_consistent();
+        }
+
+        #[test]
+        fn automatic_index_skips_used_endpoints() {
+            let mut h = Harness::new();
+            for expected in 1..ENDPOINTS.len() as u8 {
+                let index = h
+                    .alloc(EndpointType::Bulk, None, Direction::In, 64)
+                    .unwrap();
+                assert_eq!(index, expected);
+            }
+            let res = h.alloc(EndpointType::Bulk, None, Direction::In, 64);
+            assert_eq!(res, Err(EndpointAllocError::EndpointOverflow));
+            h.assert_consistent();
+        }
+
+        fn overcommit() -> AllocOptions {
+            AllocOptions {
+                overcommit_fifo: true,
+                ..high_speed_options()
+            }
+        }
+
+        #[test]
+        fn overcommit_accepts_endpoints_beyond_ram() {
+            let mut h = Harness::new();
+            // Two alternate settings, each using most of the RAM.
+            h.alloc_with(EndpointType::Isochronous, Some(1), Direction::In, 1024, overcommit()).unwrap();
+            h.alloc_with(EndpointType::Isochronous, Some(2), Direction::In, 1024, overcommit()).unwrap();
+            assert!(h.fifo.usage(&h.alloc).used_bytes > TOTAL_FIFO_SIZE);
+
+            // Even over-committed, a FIFO must fit the RAM on its own.
+            let options = AllocOptions {
+                double_buffered_endpoints: 1 << 3,
+                ..overcommit()
+            };
+            let res = h.alloc_with(EndpointType::Isochronous, Some(3), Direction::In, 1024, options);
+            assert_eq!(res, Err(EndpointAllocError::BufferOverflow));
+        }
+
+        #[test]
+        fn place_keeps_allocated_address() {
+            let mut h = Harness::new();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::In, 64).unwrap();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::Out, 64).unwrap();
+            let mut confs = h.confs();
+            let mut active = ActiveFifos::default();
+            for direction in [Direction::In, Direction::Out] {
+                let moved = place_fifo(&mut confs, active, 1, direction, |_| false).unwrap();
+                assert_eq!(moved, ActiveFifos::default());
+                active.set(1, direction, true);
+            }
+            assert_eq!(confs[1].tx_fifo_addr_8bytes, h.alloc[1].ep_conf.tx_fifo_addr_8bytes);
+            assert_eq!(confs[1].rx_fifo_addr_8bytes, h.alloc[1].ep_conf.rx_fifo_addr_8bytes);
+        }
+
+        #[test]
+        fn place_reuses_released_ram() {
+            let mut h = Harness::new();
+            h.alloc_with(EndpointType::Isochronous, Some(1), Direction::In, 1024, overcommit()).unwrap();
+            h.alloc_with(EndpointType::Isochronous, Some(2), Direction::In, 1024, overcommit()).unwrap();
+            let mut confs = h.confs();
+            let mut active = ActiveFifos::default();
+
+            place_fifo(&mut confs, active, 1, Direction::In, |_| false).unwrap();
+            active.set(1, Direction::In, true);
+            let res = place_fifo(&mut confs, active, 2, Direction::In, |_| true);
+            assert_eq!(res, Err(EndpointAllocError::BufferOverflow));
+
+            // Switching alternate settings releases endpoint 1.
+            active.set(1, Direction::In, false);
+            place_fifo(&mut confs, active, 2, Direction::In, |_| false).unwrap();
+            assert_eq!(confs[2].tx_fifo_addr_8bytes, 8);
+        }
+
+        #[test]
+        fn place_compacts_fragmented_ram() {
+            let mut h = Harness::new();
+            for (index, size) in [(1, 256), (2, 512), (3, 256), (4, 512)] {
+                h.alloc(EndpointType::Interrupt, Some(index), Direction::In, size).unwrap();
+            }
+            h.alloc_with(EndpointType::Isochronous, Some(5), Direction::Out, 512, overcommit()).unwrap();
+            let mut confs = h.confs();
+            let mut active = ActiveFifos::default();
+            for index in 1..=4 {
+                place_fifo(&mut confs, active, index, Direction::In, |_| false).unwrap();
+                active.set(index, Direction::In, true);
+            }
+            // Releasing endpoint 1 leaves 256 + 448 bytes free, in two blocks.
+            active.set(1, Direction::In, false);
+
+            let res = place_fifo(&mut confs, active, 5, Direction::Out, |_| false);
+            assert_eq!(res, Err(EndpointAllocError::FifoBusy));
+
+            let moved = place_fifo(&mut confs, active, 5, Direction::Out, |_| true).unwrap();
+            active.set(5, Direction::Out, true);
+            assert!(moved.tx != 0 && moved.rx == 0);
+
+            let mut regions = [(0u16, 0u16); 4];
+            for (n, (i, d)) in active.iter().enumerate() {
+                regions[n] = fifo_region(&confs[i], d);
+            }
+            regions.sort_unstable();
+            assert_eq!(regions, [(8, 64), (72, 64), (136, 64), (200, 32)]);
+        }
+
+        #[test]
+        fn place_never_moves_active_rx_fifo() {
+            let mut h = Harness::new();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::Out, 512).unwrap();
+            h.alloc(EndpointType::Interrupt, Some(2), Direction::Out, 512).unwrap();
+            h.alloc_with(EndpointType::Isochronous, Some(3), Direction::In, 1024, overcommit()).unwrap();
+            let mut confs = h.confs();
+            let mut active = ActiveFifos::default();
+            for index in [1, 2] {
+                place_fifo(&mut confs, active, index, Direction::Out, |_| true).unwrap();
+                active.set(index, Direction::Out, true);
+            }
+            // Releasing endpoint 1 leaves enough RAM, but only around endpoint 2.
+            active.set(1, Direction::Out, false);
+            let rx_region = fifo_region(&confs[2], Direction::Out);
+
+            let res = place_fifo(&mut confs, active, 3, Direction::In, |_| true);
+            assert_eq!(res, Err(EndpointAllocError::FifoBusy));
+            assert_eq!(fifo_region(&confs[2], Direction::Out), rx_region);
+        }
+
+        #[test]
+        fn enabled_fifo_map_reports_fragmentation() {
+            let mut h = Harness::new();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::In, 512).unwrap();
+            h.alloc(EndpointType::Interrupt, Some(2), Direction::In, 512).unwrap();
+            let mut confs = h.confs();
+            let mut active = ActiveFifos::default();
+            for index in [1, 2] {
+                place_fifo(&mut confs, active, index, Direction::In, |_| true).unwrap();
+                active.set(index, Direction::In, true);
+            }
+            let map = enabled_fifo_map(&confs, |i, d| active.contains(i, d));
+            assert_eq!(map.largest_free_bytes, map.free_bytes);
+
+            // Releasing endpoint 1 leaves a 512 byte hole in front of endpoint 2.
+            active.set(1, Direction::In, false);
+            let map = enabled_fifo_map(&confs, |i, d| active.contains(i, d));
+            assert_eq!(map.free_bytes, TOTAL_FIFO_SIZE - 64 - 512);
+            assert_eq!(map.largest_free_bytes, TOTAL_FIFO_SIZE - 64 - 1024);
+        }
+
+        #[test]
+        fn enabled_fifo_map_follows_placement() {
+            let mut h = Harness::new();
+            h.alloc(EndpointType::Interrupt, Some(1), Direction::In, 512).unwrap();
+            h.alloc(EndpointType::Interrupt, Some(2), Direction::In, 512).unwrap();
+            h.alloc_with(EndpointType::Isochronous, Some(3), Direction::Out, 1024, overcommit()).unwrap();
+            let mut confs = h.confs();
+            let mut active = ActiveFifos::default();
+            for index in [1, 2] {
+                place_fifo(&mut confs, active, index, Direction::In, |_| true).unwrap();
+                active.set(index, Direction::In, true);
+            }
+            // Endpoint 3 only fits once endpoint 1 is released and the RAM is compacted.
+            active.set(1, Direction::In, false);
+            place_fifo(&mut confs, active, 3, Direction::Out, |_| true).unwrap();
+            active.set(3, Direction::Out, true);
+
+            let map = enabled_fifo_map(&confs, |i, d| active.contains(i, d));
+            assert_eq!(map.tx[0].unwrap().fifo_size, 64);
+            assert_eq!(map.tx[1], None);
+            let (addr, size) = fifo_region(&confs[3], Direction::Out);
+            assert_eq!(map.rx[3].unwrap().fifo_addr, Some(addr * 8));
+            assert_eq!(map.rx[3].unwrap().fifo_size, size * 8);
+            assert_ne!(map.rx[3], fifo_map(&h.alloc, &h.fifo).rx[3]);
+            assert_eq!(map.free_bytes, TOTAL_FIFO_SIZE - 64 - 512 - 1024);
+        }
+
+        /// Enabling and disabling endpoints in any order never overlaps FIFOs, and
+        /// only fails when the active FIFOs leave too little RAM.
+        #[test]
+        fn exhaustive_enable_sequences() {
+            let mut h = Harness::new();
+            let sizes = [1024u16, 512, 512, 256, 128, 64, 8];
+            for (i, size) in sizes.into_iter().enumerate() {
+                h.alloc_with(EndpointType::Isochronous, Some(i as u8 + 1), Direction::In, size, overcommit()).unwrap();
+            }
+            let n = sizes.len().min(ENDPOINTS.len() - 1);
+
+            for order in 0..(1u32 << (2 * n)) {
+                let mut confs = h.confs();
+                let mut active = ActiveFifos::default();
+                for step in 0..2 * n {
+                    let index = step % n + 1;
+                    let enable = order & (1 << step) != 0;
+                    if !enable {
+                        active.set(index, Direction::In, false);
+                        continue;
+                    }
+                    let others: u16 = active
+                        .iter()
+                        .filter(|&(i, _)| i != index)
+                        .map(|(i, d)| fifo_region(&confs[i], d).1)
+                        .sum();
+                    let size = fifo_region(&confs[index], Direction::In).1;
+                    let res = place_fifo(&mut confs, active, index, Direction::In, |_| true);
+                    assert_eq!(res.is_ok(), others + size <= FIFO_END_8BYTES - EP0_FIFO_SIZE_8BYTES);
+                    if res.is_ok() {
+                        active.set(index, Direction::In, true);
+                    }
+
+                    for (i, d) in active.iter() {
+                        let (addr, size) = fifo_region(&confs[i], d);
+                        assert!(addr >= EP0_FIFO_SIZE_8BYTES && addr + size <= FIFO_END_8BYTES);
+                        for (j, e) in active.iter() {
+                            if (i, d) != (j, e) {
+                                let (other_addr, other_size) = fifo_region(&confs[j], e);
+                                assert!(addr + size <= other_addr || other_addr + other_size <= addr);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn fifo_map_describes_allocation() {
+            let mut h = Harness::new();
+            let options = AllocOptions {
+                double_buffered_endpoints: 1 << 1,
+                ..high_speed_options()
+            };
+            h.alloc_with(EndpointType::Bulk, Some(1), Direction::In, 64, options).unwrap();
+            h.alloc(EndpointType::Interrupt, Some(2), Direction::Out, 10).unwrap();
+
+            let map = fifo_map(&h.alloc, &h.fifo);
+            assert_eq!(
+                map.tx[1],
+                Some(FifoMapEntry {
+                    ep_type: EndpointType::Bulk,
+                    max_packet_size: 64,
+                    fifo_addr: Some(64),
+                    fifo_size: 128,
+                    double_buffered: true,
+                })
+            );
+            assert_eq!(
+                map.rx[2],
+                Some(FifoMapEntry {
+                    ep_type: EndpointType::Interrupt,
+                    max_packet_size: 10,
+                    fifo_addr: Some(TOTAL_FIFO_SIZE - 16),
+                    fifo_size: 16,
+                    double_buffered: false,
+                })
+            );
+            assert_eq!(map.tx[0].unwrap().fifo_size, 64);
+            assert_eq!(map.rx[1], None);
+            assert_eq!(map.free_bytes, TOTAL_FIFO_SIZE - 64 - 128 - 16);
+        }
+
+        #[test]
+        fn endpoint_plan_fills_ram() {
+            // 64 (EP0) + 1024 + 2 * 256 + 2 * 128 + 128 + 64 = 2048
+            EndpointPlan::new([
+                PlannedEndpoint::new(0x81, EndpointType::Isochronous, 1000),
+                PlannedEndpoint::new(0x02, EndpointType::Bulk, 256).double_buffered(),
+                PlannedEndpoint::new(0x83, EndpointType::Interrupt, 100).double_buffered(),
+                PlannedEndpoint::new(0x04, EndpointType::Interrupt, 65),
+                PlannedEndpoint::new(0x85, EndpointType::Interrupt, 64),
+            ]);
+        }
+
+        #[test]
+        #[should_panic(expected = "more FIFO RAM than available")]
+        fn endpoint_plan_rejects_ram_overflow() {
+            EndpointPlan::new([
+                PlannedEndpoint::new(0x81, EndpointType::Isochronous, 1024),
+                PlannedEndpoint::new(0x02, EndpointType::Isochronous, 1024),
+            ]);
+        }
+
+        #[test]
+        fn endpoint_plan_accepts_bulk_pair() {
+            EndpointPlan::new([
+                PlannedEndpoint::new(0x81, EndpointType::Bulk, 64),
+                PlannedEndpoint::new(0x01, EndpointType::Bulk, 64),
+            ]);
+        }
+
+        #[test]
+        #[should_panic(expected = "same type")]
+        fn endpoint_plan_rejects_mixed_types_on_index() {
+            EndpointPlan::new([
+                PlannedEndpoint::new(0x81, EndpointType::Bulk, 64),
+                PlannedEndpoint::new(0x01, EndpointType::Interrupt, 64),
+            ]);
+        }
+
+        #[test]
+        fn bulk_in_and_out_share_index() {
+            let mut h = Harness::new();
+            assert_eq!(h.alloc(EndpointType::Bulk, Some(1), Direction::In, 64), Ok(1));
+            assert_eq!(h.alloc(EndpointType::Bulk, Some(1), Direction::Out, 64), Ok(1));
+            assert_eq!(h.alloc(EndpointType::Bulk, Some(1), Direction::Out, 64), Err(EndpointAllocError::EpUsed));
+            assert_eq!(h.alloc(EndpointType::Interrupt, Some(2), Direction::In, 64), Ok(2));
+            assert_eq!(h.alloc(EndpointType::Bulk, Some(2), Direction::Out, 64), Err(EndpointAllocError::EpUsed));
+            // Automatic allocation pairs OUT with the IN endpoint already allocated.
+            assert_eq!(h.alloc(EndpointType::Bulk, None, Direction::In, 64), Ok(3));
+            assert_eq!(h.alloc(EndpointType::Bulk, None, Direction::Out, 64), Ok(3));
+            h.assert_consistent();
+        }
+
+        #[test]
+        #[should_panic(expected = "used twice")]
+        fn endpoint_plan_rejects_duplicate_address() {
+            EndpointPlan::new([
+                PlannedEndpoint::new(0x81, EndpointType::Interrupt, 64),
+                PlannedEndpoint::new(0x81, EndpointType::Interrupt, 64),
+            ]);
+        }
+
+        /// Every pair of power-of-two sizes, directions and buffering modes either fits
+        /// without overlap, or fails exactly when the RAM is too small.
+        #[test]
+        fn exhaustive_pairs() {
+            let sizes = [0u16, 8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+            let directions = [Direction::In, Direction::Out];
+            for &size_a in &sizes {
+                for &size_b in &sizes {
+                    for &dir_a in &directions {
+                        for &dir_b in &directions {
+                            for dbl in 0..4u16 {
+                                let mut h = Harness::new();
+                                let options = AllocOptions {
+                                    double_buffered_endpoints: dbl << 1,
+                                    ..high_speed_options()
+                                };
+                                let block = |size: u16, index: u16| {
+                                    let double = dbl & (1 << (index - 1)) != 0 && size * 2 <= 2048;
+                                    size.max(8) * if double { 2 } else { 1 }
+                                };
+
+                                let free = TOTAL_FIFO_SIZE - 64;
+                                let res_a = h.alloc_with(
+                                    EndpointType::Isochronous,
+                                    Some(1),
+                                    dir_a,
+                                    size_a,
+                                    options,
+                                );
+                                assert_eq!(res_a.is_ok(), block(size_a, 1) <= free);
+                                let used_a = if res_a.is_ok() { block(size_a, 1) } else { 0 };
+
+                                let res_b = h.alloc_with(
+                                    EndpointType::Isochronous,
+                                    Some(2),
+                                    dir_b,
+                                    size_b,
+                                    options,
+                                );
+                                assert_eq!(res_b.is_ok(), used_a + block(size_b, 2) <= free);
+
+                                h.assert_consistent();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
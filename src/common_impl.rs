@@ -1,21 +1,190 @@
 use embassy_usb_driver::EndpointType;
 
 use crate::alloc_endpoint::EndpointConfig;
-use crate::regs::regs::{Intrrxe, Intrtxe};
+use crate::common::{Reg, RW};
+use crate::regs::regs::{Fifo, Intrrxe, Intrtxe, Intrusbe};
 #[cfg(feature = "_ep-shared-fifo")]
 use crate::regs::vals::EndpointDirection;
+#[cfg(not(feature = "_lite"))]
+use crate::regs::vals::HsModeStatus;
 use crate::{trace, warn, MusbInstance};
 use crate::info::ENDPOINTS;
 
 pub fn bus_init<T: MusbInstance>() {
-    T::regs().intrusbe().write(|w| {
+    // Other bits (SOF, VBUS session) are owned by their users and kept as they are.
+    T::regs().intrusbe().modify(|w| {
         w.set_reset_enable(true);
         w.set_suspend_enable(true);
         w.set_resume_enable(true);
     });
 
-    T::regs().intrrxe().write_value(Intrrxe(0xFE));
-    T::regs().intrtxe().write_value(Intrtxe(0xFF));
+    // Only EP0 is unmasked, `ep_tx_enable`/`ep_rx_enable` unmask the other endpoints.
+    T::regs().intrrxe().write_value(Intrrxe(0));
+    T::regs().intrtxe().write_value(Intrtxe(0x01));
+}
+
+/// Connects or disconnects the D+/D- pull-up through `POWER.Soft_Conn`.
+#[cfg(not(feature = "_lite"))]
+pub fn set_soft_connect<T: MusbInstance>(connected: bool) {
+    T::regs().power().modify(|w| w.set_soft_conn(connected));
+}
+
+/// Requests high-speed operation through `POWER.HS_Enab`, it is negotiated on the next reset.
+#[cfg(not(feature = "_lite"))]
+pub fn set_high_speed_enabled<T: MusbInstance>(enabled: bool) {
+    T::regs().power().modify(|w| w.set_hs_enab(enabled));
+}
+
+/// Returns whether high speed was negotiated during the last reset (`POWER.HS_Mode`).
+pub fn is_high_speed<T: MusbInstance>() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "_lite")] {
+            false
+        } else {
+            T::regs().power().read().hs_mode() == HsModeStatus::HighSpeed
+        }
+    }
+}
+
+/// Largest max packet size the USB specification allows for `ep_type` at the given speed.
+pub fn max_packet_size_limit(ep_type: EndpointType, high_speed: bool) -> u16 {
+    match (ep_type, high_speed) {
+        (EndpointType::Control, _) => 64,
+        (EndpointType::Bulk, false) | (EndpointType::Interrupt, false) => 64,
+        (EndpointType::Bulk, true) => 512,
+        (EndpointType::Interrupt, true) | (EndpointType::Isochronous, true) => 1024,
+        (EndpointType::Isochronous, false) => 1023,
+    }
+}
+
+/// Whether `max_packet_size` is allowed at the speed negotiated during the last
+/// reset, warns if not.
+pub fn max_packet_size_allowed<T: MusbInstance>(index: u8, ep_type: EndpointType, max_packet_size: u16) -> bool {
+    let high_speed = is_high_speed::<T>();
+    let limit = max_packet_size_limit(ep_type, high_speed);
+    if max_packet_size > limit {
+        warn!(
+            "musb/ep_enable: endpoint {} max_packet_size={} exceeds {} for {:?} at {}",
+            index,
+            max_packet_size,
+            limit,
+            ep_type,
+            if high_speed { "high speed" } else { "full speed" }
+        );
+        return false;
+    }
+    true
+}
+
+/// USB 2.0 electrical test modes, entered through the `TESTMODE` register.
+#[cfg(not(feature = "_lite"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TestMode {
+    /// Test_J: drive a constant J state.
+    J,
+    /// Test_K: drive a constant K state.
+    K,
+    /// Test_SE0_NAK: stay in high-speed receive mode and NAK every IN token.
+    Se0Nak,
+    /// Test_Packet: repeatedly send the standard test packet.
+    Packet,
+    /// Force high speed on the next reset, regardless of the host.
+    ForceHighSpeed,
+    /// Force full speed on the next reset.
+    ForceFullSpeed,
+}
+
+#[cfg(not(feature = "_lite"))]
+impl TestMode {
+    /// Parses the test selector of SET_FEATURE(TEST_MODE), the high byte of `wIndex`.
+    pub fn from_selector(selector: u8) -> Option<Self> {
+        match selector {
+            0x01 => Some(Self::J),
+            0x02 => Some(Self::K),
+            0x03 => Some(Self::Se0Nak),
+            0x04 => Some(Self::Packet),
+            _ => None,
+        }
+    }
+}
+
+/// Test packet sent in [`TestMode::Packet`], USB 2.0 specification section 7.1.20.
+#[cfg(not(feature = "_lite"))]
+const TEST_PACKET: [u8; 53] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA,
+    0xAA, 0xAA, 0xAA, 0xEE, 0xEE, 0xEE, 0xEE, 0xEE, 0xEE, 0xEE, 0xEE, 0xFE, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0xBF, 0xDF, 0xEF, 0xF7,
+    0xFB, 0xFD, 0xFC, 0x7E, 0xBF, 0xDF, 0xEF, 0xF7, 0xFB, 0xFD, 0x7E,
+];
+
+/// Enters a test mode once SET_FEATURE(TEST_MODE) has completed, exiting it takes a power cycle (USB 2.0 7.1.20).
+#[cfg(not(feature = "_lite"))]
+pub fn enter_test_mode<T: MusbInstance>(mode: TestMode) {
+    trace!("musb/test_mode: entering {:?}", mode);
+    let regs = T::regs();
+    regs.index().write(|w| w.set_index(0));
+
+    if mode == TestMode::Packet {
+        fifo_write::<T>(0, &TEST_PACKET);
+    }
+    regs.testmode().write(|w| match mode {
+        TestMode::J => w.set_test_j(true),
+        TestMode::K => w.set_test_k(true),
+        TestMode::Se0Nak => w.set_test_se0_nak(true),
+        TestMode::Packet => w.set_test_packet(true),
+        TestMode::ForceHighSpeed => w.set_force_hs(true),
+        TestMode::ForceFullSpeed => w.set_force_fs(true),
+    });
+    if mode == TestMode::Packet {
+        regs.csr0l().modify(|w| w.set_tx_pkt_rdy(true));
+    }
+}
+
+/// Returns the frame number of the last received SOF.
+pub fn frame_number<T: MusbInstance>() -> u16 {
+    T::regs().frame().read().frame()
+}
+
+/// Disconnects from the bus, masks all interrupts and flushes all FIFOs.
+///
+/// The VBUS session interrupts stay armed, so that a replug is still seen.
+pub fn bus_disable<T: MusbInstance>() {
+    let regs = T::regs();
+
+    #[cfg(not(feature = "_lite"))]
+    set_soft_connect::<T>(false);
+
+    #[cfg(feature = "_lite")]
+    let intrusbe = Intrusbe(0);
+    #[cfg(not(feature = "_lite"))]
+    let intrusbe = {
+        let armed = regs.intrusbe().read();
+        let mut intrusbe = Intrusbe(0);
+        intrusbe.set_discon_enable(armed.discon_enable());
+        intrusbe.set_sess_req_enable(armed.sess_req_enable());
+        intrusbe
+    };
+    regs.intrusbe().write_value(intrusbe);
+    regs.intrtxe().write_value(Intrtxe(0));
+    regs.intrrxe().write_value(Intrrxe(0));
+
+    regs.index().write(|w| w.set_index(0));
+    #[cfg(not(feature = "_lite"))]
+    regs.csr0h().modify(|w| w.set_flush_fifo(true));
+
+    for index in 1..ENDPOINTS.len() {
+        regs.index().write(|w| w.set_index(index as _));
+        // Flush twice in case the FIFO is double-buffered.
+        if regs.txcsrl().read().fifo_not_empty() {
+            regs.txcsrl().modify(|w| w.set_flush_fifo(true));
+            regs.txcsrl().modify(|w| w.set_flush_fifo(true));
+        }
+        if regs.rxcsrl().read().rx_pkt_rdy() {
+            regs.rxcsrl().modify(|w| w.set_flush_fifo(true));
+            regs.rxcsrl().modify(|w| w.set_flush_fifo(true));
+        }
+    }
 }
 
 pub fn ep_tx_stall<T: MusbInstance>(index: u8, stalled: bool) {
@@ -87,6 +256,59 @@ pub fn ep_tx_is_stalled<T: MusbInstance>(index: u8) -> bool {
     }
 }
 
+/// Whether the FIFO of a TX endpoint holds no packet, so it can be moved.
+#[cfg(not(feature = "_fixed-fifo-size"))]
+pub fn ep_tx_fifo_is_empty<T: MusbInstance>(index: u8) -> bool {
+    let regs = T::regs();
+    regs.index().write(|w| w.set_index(index));
+    let csr = regs.txcsrl().read();
+    !csr.tx_pkt_rdy() && !csr.fifo_not_empty()
+}
+
+/// Re-programs the FIFO address of an enabled TX endpoint after compaction.
+#[cfg(not(feature = "_fixed-fifo-size"))]
+pub fn ep_tx_set_fifo_addr<T: MusbInstance>(index: u8, config: &EndpointConfig) {
+    trace!("musb/ep_tx_set_fifo_addr: TX endpoint {} moved to {}", index, config.tx_fifo_addr_8bytes);
+    let regs = T::regs();
+    regs.index().write(|w| w.set_index(index));
+    regs.tx_fifo_add().write(|w| w.set_add(config.tx_fifo_addr_8bytes));
+}
+
+/// Masks the interrupt of a TX endpoint and flushes its FIFO.
+pub fn ep_tx_disable<T: MusbInstance>(index: u8) {
+    trace!("musb/ep_disable: Disabling TX endpoint {}", index);
+    let regs = T::regs();
+    regs.intrtxe().modify(|w| w.set_ep_txe(index as _, false));
+
+    regs.index().write(|w| w.set_index(index));
+    // The FIFO may be serving the other direction.
+    #[cfg(feature = "_ep-shared-fifo")]
+    if regs.txcsrh().read().mode() != EndpointDirection::Tx {
+        return;
+    }
+    if regs.txcsrl().read().fifo_not_empty() {
+        regs.txcsrl().modify(|w| w.set_flush_fifo(true));
+        regs.txcsrl().modify(|w| w.set_flush_fifo(true));
+    }
+}
+
+/// Masks the interrupt of an RX endpoint and flushes its FIFO.
+pub fn ep_rx_disable<T: MusbInstance>(index: u8) {
+    trace!("musb/ep_disable: Disabling RX endpoint {}", index);
+    let regs = T::regs();
+    regs.intrrxe().modify(|w| w.set_ep_rxe(index as _, false));
+
+    regs.index().write(|w| w.set_index(index));
+    #[cfg(feature = "_ep-shared-fifo")]
+    if regs.txcsrh().read().mode() != EndpointDirection::Rx {
+        return;
+    }
+    if regs.rxcsrl().read().rx_pkt_rdy() {
+        regs.rxcsrl().modify(|w| w.set_flush_fifo(true));
+        regs.rxcsrl().modify(|w| w.set_flush_fifo(true));
+    }
+}
+
 pub fn ep_tx_enable<T: MusbInstance>(index: u8, config: &EndpointConfig) {
     #[cfg(not(feature="_fixed-fifo-size"))]
     trace!(
@@ -122,23 +344,22 @@ pub fn ep_tx_enable<T: MusbInstance>(index: u8, config: &EndpointConfig) {
             .modify(|w| w.set_ep_txe(index as _, true));
     }
 
-    // T::regs().txcsrh().write(|w| {
-    //     w.set_auto_set(true);
-    // });
-
     // TODO: DMA
 
     if index != 0 {
+
         // This logic is only compiled when we are NOT using fixed FIFOs.
         #[cfg(not(feature = "_fixed-fifo-size"))]
         {
             T::regs().tx_fifo_sz().write(|w| {
                 let size_code = (config.tx_fifo_size_bits - 3) as u8;
                 w.set_sz(size_code);
-                w.set_dpb(true);
+                w.set_dpb(config.tx_double_buffered);
             });
             T::regs().tx_fifo_add().write(|w| w.set_add(config.tx_fifo_addr_8bytes));
         }
+        #[cfg(not(feature = "_lite"))]
+        endpoint_set_tx_dualpacket_enabled::<T>(index, config.tx_double_buffered);
 
         cfg_if::cfg_if! {
             if #[cfg(feature = "_lite")] {
@@ -154,26 +375,28 @@ pub fn ep_tx_enable<T: MusbInstance>(index: u8, config: &EndpointConfig) {
                     .write(|w| w.set_maxp((config.tx_max_packet_size + 7) / 8));
             } else {
                 // Full version uses full packet size
-                T::regs()
-                    .txmaxp()
-                    .write(|w| w.set_maxp(config.tx_max_packet_size));
+                T::regs().txmaxp().write(|w| {
+                    w.set_maxp(config.tx_max_packet_size);
+                    w.set_mult(config.tx_packet_multiplier - 1);
+                });
             }
         }
 
+        #[cfg(feature = "_ep-shared-fifo")]
+        if T::regs().txcsrh().read().mode() != EndpointDirection::Tx {
+            shared_fifo_switch::<T>(index);
+        }
+
         T::regs().txcsrl().write(|w| {
             w.set_clr_data_tog(true);
         });
 
-        if config.ep_type == EndpointType::Isochronous {
-            T::regs().txcsrh().write(|w| {
-                w.set_iso(true);
-            });
-        }
-
-        #[cfg(feature = "_ep-shared-fifo")]
-        T::regs()
-            .txcsrh()
-            .write(|w| w.set_mode(EndpointDirection::Tx));
+        T::regs().txcsrh().write(|w| {
+            w.set_iso(config.ep_type == EndpointType::Isochronous);
+            w.set_auto_set(config.tx_auto_set);
+            #[cfg(feature = "_ep-shared-fifo")]
+            w.set_mode(EndpointDirection::Tx);
+        });
 
         if T::regs().txcsrl().read().fifo_not_empty() {
             T::regs().txcsrl().modify(|w| w.set_flush_fifo(true));
@@ -219,19 +442,18 @@ pub fn ep_rx_enable<T: MusbInstance>(index: u8, config: &EndpointConfig) {
             .intrrxe()
             .modify(|w| w.set_ep_rxe(index as _, true));
 
-        // T::regs().rxcsrh().write(|w| {
-        //     w.set_auto_clear(true);
-        // });
 
         #[cfg(not(feature = "_fixed-fifo-size"))]
         {
             T::regs().rx_fifo_sz().write(|w| {
                 let size_code = (config.rx_fifo_size_bits - 3) as u8;
                 w.set_sz(size_code);
-                w.set_dpb(true);
+                w.set_dpb(config.rx_double_buffered);
             });
             T::regs().rx_fifo_add().write(|w| w.set_add(config.rx_fifo_addr_8bytes));
         }
+        #[cfg(not(feature = "_lite"))]
+        endpoint_set_rx_dualpacket_enabled::<T>(index, config.rx_double_buffered);
     
         cfg_if::cfg_if! {
             if #[cfg(feature = "_lite")] {
@@ -245,12 +467,18 @@ pub fn ep_rx_enable<T: MusbInstance>(index: u8, config: &EndpointConfig) {
                     .rxmaxp()
                     .write(|w| w.set_maxp((config.rx_max_packet_size + 7) / 8));
             } else {
-                T::regs()
-                    .rxmaxp()
-                    .write(|w| w.set_maxp(config.rx_max_packet_size));
+                T::regs().rxmaxp().write(|w| {
+                    w.set_maxp(config.rx_max_packet_size);
+                    w.set_mult(config.rx_packet_multiplier - 1);
+                });
             }
         }
 
+        #[cfg(feature = "_ep-shared-fifo")]
+        if T::regs().txcsrh().read().mode() != EndpointDirection::Rx {
+            shared_fifo_switch::<T>(index);
+        }
+
         T::regs().rxcsrl().write(|w| {
             w.set_clr_data_tog(true);
         });
@@ -262,11 +490,10 @@ pub fn ep_rx_enable<T: MusbInstance>(index: u8, config: &EndpointConfig) {
 
         //TODO: DMA
 
-        if config.ep_type == EndpointType::Isochronous {
-            T::regs().rxcsrh().write(|w| {
-                w.set_iso(true);
-            });
-        }
+        T::regs().rxcsrh().write(|w| {
+            w.set_iso(config.ep_type == EndpointType::Isochronous);
+            w.set_auto_clear(config.rx_auto_clear);
+        });
 
         if T::regs().rxcsrl().read().rx_pkt_rdy() {
             T::regs().rxcsrl().modify(|w| w.set_flush_fifo(true));
@@ -275,6 +502,86 @@ pub fn ep_rx_enable<T: MusbInstance>(index: u8, config: &EndpointConfig) {
     }
 }
 
+/// Empties the FIFO of a shared FIFO endpoint and resets both data toggles before
+/// its direction changes. The endpoint must be selected in `INDEX`.
+#[cfg(feature = "_ep-shared-fifo")]
+fn shared_fifo_switch<T: MusbInstance>(index: u8) {
+    trace!("musb/ep_enable: switching direction of shared FIFO endpoint {}", index);
+    let regs = T::regs();
+    if regs.txcsrl().read().fifo_not_empty() {
+        regs.txcsrl().modify(|w| w.set_flush_fifo(true));
+        regs.txcsrl().modify(|w| w.set_flush_fifo(true));
+    }
+    if regs.rxcsrl().read().rx_pkt_rdy() {
+        regs.rxcsrl().modify(|w| w.set_flush_fifo(true));
+        regs.rxcsrl().modify(|w| w.set_flush_fifo(true));
+    }
+    regs.txcsrl().write(|w| w.set_clr_data_tog(true));
+    regs.rxcsrl().write(|w| w.set_clr_data_tog(true));
+}
+
+/// Whether whole words are moved with 32-bit FIFO accesses.
+///
+/// Either the FIFO register is declared 32-bit wide (`reg_bit_size.fifo`), or the
+/// core tolerates 32-bit accesses mixed with byte accesses.
+const FIFO_WORD_ACCESS: bool =
+    cfg!(feature = "_fifo-mixed-width") || core::mem::size_of::<Fifo>() == 4;
+
+/// Copies `buf` into the FIFO of endpoint `index`.
+///
+/// Whole words use 32-bit accesses when [`FIFO_WORD_ACCESS`] allows it, the tail
+/// goes byte by byte.
+pub fn fifo_write<T: MusbInstance>(index: usize, buf: &[u8]) {
+    let fifo = T::regs().fifo(index);
+    let word_len = if FIFO_WORD_ACCESS { buf.len() & !3 } else { 0 };
+    let (words, tail) = buf.split_at(word_len);
+
+    for word in words.chunks_exact(4) {
+        let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        unsafe { (fifo.as_ptr() as *mut u32).write_volatile(word) };
+    }
+    for b in tail {
+        fifo_write_byte(fifo, *b);
+    }
+}
+
+/// Fills `buf` from the FIFO of endpoint `index`, see [`fifo_write`].
+pub fn fifo_read<T: MusbInstance>(index: usize, buf: &mut [u8]) {
+    let fifo = T::regs().fifo(index);
+    let word_len = if FIFO_WORD_ACCESS { buf.len() & !3 } else { 0 };
+    let (words, tail) = buf.split_at_mut(word_len);
+
+    for word in words.chunks_exact_mut(4) {
+        let val = unsafe { (fifo.as_ptr() as *mut u32).read_volatile() };
+        word.copy_from_slice(&val.to_le_bytes());
+    }
+    for b in tail {
+        *b = fifo_read_byte(fifo);
+    }
+}
+
+#[inline(always)]
+fn fifo_write_byte(fifo: Reg<Fifo, RW>, b: u8) {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "_fifo-mixed-width")] {
+            unsafe { (fifo.as_ptr() as *mut u8).write_volatile(b) };
+        } else {
+            fifo.write(|w| w.set_data(b));
+        }
+    }
+}
+
+#[inline(always)]
+fn fifo_read_byte(fifo: Reg<Fifo, RW>) -> u8 {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "_fifo-mixed-width")] {
+            unsafe { (fifo.as_ptr() as *mut u8).read_volatile() }
+        } else {
+            fifo.read().data()
+        }
+    }
+}
+
 #[allow(unused)]
 pub fn check_overrun<T: MusbInstance>() {
     let regs = T::regs();
@@ -1,14 +1,55 @@
 use super::*;
 
-use crate::alloc_endpoint::EndpointConfig;
+use crate::alloc_endpoint::{self, EndpointConfig, FifoMap};
+#[cfg(not(feature = "_fixed-fifo-size"))]
+use crate::alloc_endpoint::ActiveFifos;
 use crate::common_impl;
 use crate::info::ENDPOINTS;
+use crate::regs::vals::IsoUpdateMode;
+
+/// Idle time after the suspend interrupt before remote wakeup may signal resume.
+const REMOTE_WAKEUP_IDLE_MS: u64 = 5;
+
+/// Waits until the clock reaches `deadline`, the clock alarm wakes the task.
+async fn wait_until_ms(clock: Clock, deadline: u64) {
+    poll_fn(|cx| {
+        if (clock.now_ms)() >= deadline {
+            Poll::Ready(())
+        } else {
+            (clock.schedule_wake)(deadline, cx.waker());
+            Poll::Pending
+        }
+    })
+    .await
+}
 
 /// USB bus.
 pub struct Bus<'d, T: MusbInstance> {
     pub(super) phantom: PhantomData<&'d mut T>,
     pub(super) ep_confs: [EndpointConfig; ENDPOINTS.len()],
+    /// Endpoints currently holding FIFO RAM, released when they are disabled.
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    pub(super) fifo_active: ActiveFifos,
+    /// IN endpoints (bit `n` for endpoint `n`) whose enable waits for another
+    /// endpoint to be disabled.
+    pub(super) pending_in: u16,
+    /// OUT endpoints whose enable waits for another endpoint to be disabled.
+    pub(super) pending_out: u16,
     pub(super) inited: bool,
+    pub(super) config: Config,
+    pub(super) power_present: bool,
+    pub(super) suspended: bool,
+    /// Clock time when the last suspend was detected.
+    pub(super) suspended_at_ms: u64,
+    /// Clock time when `poll` attaches again after [`Bus::reconnect`].
+    #[cfg(not(feature = "_lite"))]
+    pub(super) reattach_at_ms: Option<u64>,
+    /// In L1 sleep, entered through an accepted LPM transaction.
+    #[cfg(not(feature = "_lite"))]
+    pub(super) l1: bool,
+    /// Remote wakeup from L1 was allowed by the host (`LPM_ATTR.RmtWak`).
+    #[cfg(not(feature = "_lite"))]
+    pub(super) l1_remote_wake: bool,
 }
 
 impl<'d, T: MusbInstance> Bus<'d, T> {
@@ -16,6 +57,265 @@ impl<'d, T: MusbInstance> Bus<'d, T> {
         #[cfg(not(feature="_lite"))]
         trace!("musb/bus init: DEVCTL: {:b}", T::regs().devctl().read().0);
         common_impl::bus_init::<T>();
+        // `disable` masked SOF, a pending `wait_sof` still needs it.
+        if SOF_IRQ_USERS.load(Ordering::SeqCst) > 0 {
+            T::regs().intrusbe().modify(|w| w.set_sof_enable(true));
+        }
+
+        T::regs().power().modify(|w| {
+            w.set_iso_update(if self.config.iso_update {
+                IsoUpdateMode::WaitSof
+            } else {
+                IsoUpdateMode::Normal
+            })
+        });
+        #[cfg(not(feature = "_lite"))]
+        common_impl::set_high_speed_enabled::<T>(self.config.high_speed);
+        #[cfg(not(feature = "_lite"))]
+        self.lpm_init();
+
+        #[cfg(not(feature = "_lite"))]
+        if let VbusDetect::Interrupt = self.config.vbus_detect {
+            // The interrupts only report changes, start from the current level.
+            let session = T::regs().devctl().read().vbus() != crate::regs::vals::VbusLevel::BelowSessionEnd;
+            VBUS_SESSION.store(session, Ordering::Release);
+            T::regs().intrusbe().modify(|w| {
+                w.set_discon_enable(true);
+                w.set_sess_req_enable(true);
+            });
+        }
+    }
+
+    #[cfg(not(feature = "_lite"))]
+    fn lpm_init(&self) {
+        use crate::regs::vals::LpmSupport;
+
+        let enabled = self.config.lpm != LpmPolicy::Disabled;
+        LPM_ENABLED.store(enabled, Ordering::Release);
+        if !enabled {
+            return;
+        }
+
+        let regs = T::regs();
+        regs.lpm_intren().write(|w| {
+            w.set_lpmacken(true);
+            w.set_lpmresen(true);
+        });
+        regs.lpm_cntrl().write(|w| {
+            w.set_lpmen(LpmSupport::LpmExtended);
+            w.set_lpmxmt(self.config.lpm == LpmPolicy::Accept);
+        });
+    }
+
+    /// Arms the ACK response for the next LPM transaction, `LPMXMT` clears itself
+    /// once it has been used.
+    #[cfg(not(feature = "_lite"))]
+    fn lpm_rearm(&self) {
+        if self.config.lpm == LpmPolicy::Accept {
+            T::regs().lpm_cntrl().modify(|w| w.set_lpmxmt(true));
+        }
+    }
+
+    fn vbus_present(&self) -> bool {
+        match self.config.vbus_detect {
+            VbusDetect::None => true,
+            #[cfg(not(feature = "_lite"))]
+            VbusDetect::Devctl => {
+                use crate::regs::vals::VbusLevel;
+                T::regs().devctl().read().vbus() >= VbusLevel::AboveAvalidBelowVbusValid
+            }
+            #[cfg(not(feature = "_lite"))]
+            VbusDetect::Interrupt => {
+                use crate::regs::vals::VbusLevel;
+                // Catch up with a session end or request whose interrupt was missed.
+                let session = T::regs().devctl().read().vbus() != VbusLevel::BelowSessionEnd;
+                if session != VBUS_SESSION.load(Ordering::Acquire) {
+                    VBUS_SESSION.store(session, Ordering::Release);
+                }
+                session
+            }
+            VbusDetect::Callback(f) => f(),
+        }
+    }
+
+    fn pending(&mut self, direction: Direction) -> &mut u16 {
+        match direction {
+            Direction::Out => &mut self.pending_out,
+            Direction::In => &mut self.pending_in,
+        }
+    }
+
+    /// Enables an endpoint, or defers it until the endpoint in its way is disabled.
+    fn enable_endpoint(&mut self, index: usize, direction: Direction) {
+        *self.pending(direction) &= !(1 << index);
+
+        // A shared FIFO serves one direction at a time. embassy-usb enables the
+        // endpoints of a new alternate setting before disabling the old ones.
+        #[cfg(feature = "_ep-shared-fifo")]
+        if index != 0 {
+            let other_enabled = match direction {
+                Direction::Out => EP_TX_ENABLED.load(Ordering::Acquire),
+                Direction::In => EP_RX_ENABLED.load(Ordering::Acquire),
+            } & (1 << index) != 0;
+            if other_enabled {
+                trace!("musb/bus: shared FIFO endpoint {} busy, enable deferred", index);
+                *self.pending(direction) |= 1 << index;
+                return;
+            }
+        }
+
+        // The host may have fallen back to full speed, the endpoint stays disabled
+        // until the next reset if its max packet size is not allowed anymore.
+        let max_packet_size = match direction {
+            Direction::In => self.ep_confs[index].tx_max_packet_size,
+            Direction::Out => self.ep_confs[index].rx_max_packet_size,
+        };
+        if !common_impl::max_packet_size_allowed::<T>(index as _, self.ep_confs[index].ep_type, max_packet_size) {
+            return;
+        }
+
+        // An over-committed FIFO waits for another endpoint to release RAM.
+        #[cfg(not(feature = "_fixed-fifo-size"))]
+        if index != 0 && !self.place_fifo(index, direction) {
+            *self.pending(direction) |= 1 << index;
+            return;
+        }
+
+        T::regs().index().write(|w| w.set_index(index as u8));
+        match direction {
+            Direction::Out => {
+                common_impl::ep_rx_enable::<T>(index as _, &self.ep_confs[index]);
+
+                let flags = EP_RX_ENABLED.load(Ordering::Acquire) | (1 << index) as u16;
+                EP_RX_ENABLED.store(flags, Ordering::Release);
+                // Wake for `Endpoint::wait_enabled()`
+                EP_RX_WAKERS[index].wake();
+            }
+            Direction::In => {
+                common_impl::ep_tx_enable::<T>(index as _, &self.ep_confs[index]);
+
+                let flags = EP_TX_ENABLED.load(Ordering::Acquire) | (1 << index) as u16;
+                EP_TX_ENABLED.store(flags, Ordering::Release);
+                // Wake for `Endpoint::wait_enabled()`
+                EP_TX_WAKERS[index].wake();
+            }
+        }
+    }
+
+    /// Retries the deferred enables.
+    fn enable_pending(&mut self) {
+        for index in 1..ENDPOINTS.len() {
+            for direction in [Direction::In, Direction::Out] {
+                if *self.pending(direction) & (1 << index) != 0 {
+                    self.enable_endpoint(index, direction);
+                }
+            }
+        }
+    }
+
+    /// Disables an endpoint, failing its pending reads and writes with `Disabled`.
+    fn disable_endpoint(&mut self, index: usize, direction: Direction) {
+        *self.pending(direction) &= !(1 << index);
+        // py32 official CherryUsb port does nothing when disable an endpoint
+        #[cfg(not(feature = "_fixed-fifo-size"))]
+        self.fifo_active.set(index, direction, false);
+        match direction {
+            Direction::Out => {
+                let flags = EP_RX_ENABLED.load(Ordering::Acquire) & !((1 << index) as u16);
+                EP_RX_ENABLED.store(flags, Ordering::Release);
+                // EP0 keeps its interrupt, it also signals SETUP packets.
+                if index != 0 {
+                    common_impl::ep_rx_disable::<T>(index as _);
+                }
+                EP_RX_WAKERS[index].wake();
+            }
+            Direction::In => {
+                let flags = EP_TX_ENABLED.load(Ordering::Acquire) & !((1 << index) as u16);
+                EP_TX_ENABLED.store(flags, Ordering::Release);
+                if index != 0 {
+                    common_impl::ep_tx_disable::<T>(index as _);
+                }
+                EP_TX_WAKERS[index].wake();
+            }
+        }
+        self.enable_pending();
+    }
+
+    /// Places the FIFO of an endpoint about to be enabled, compacting the FIFO RAM
+    /// if needed. Returns `false` if the FIFO does not fit.
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    fn place_fifo(&mut self, index: usize, direction: Direction) -> bool {
+        let moved = alloc_endpoint::place_fifo(
+            &mut self.ep_confs,
+            self.fifo_active,
+            index,
+            direction,
+            |i| common_impl::ep_tx_fifo_is_empty::<T>(i as _),
+        );
+        let moved = match moved {
+            Ok(moved) => moved,
+            Err(e) => {
+                warn!("musb/bus: no FIFO RAM for endpoint {} {:?}, enable deferred: {:?}", index, direction, e);
+                return false;
+            }
+        };
+
+        // Compaction only moves IN FIFOs.
+        for i in 1..ENDPOINTS.len() {
+            if moved.contains(i, Direction::In) {
+                common_impl::ep_tx_set_fifo_addr::<T>(i as _, &self.ep_confs[i]);
+            }
+        }
+        self.fifo_active.set(index, direction, true);
+        true
+    }
+
+    /// Enters a USB 2.0 electrical test mode.
+    ///
+    /// The control pipe already answers SET_FEATURE(TEST_MODE) at high speed, this is
+    /// for entering a test mode by other means, e.g. a vendor request. It must be
+    /// called after the status stage of that request has completed.
+    #[cfg(not(feature = "_lite"))]
+    pub fn enter_test_mode(&mut self, mode: TestMode) {
+        common_impl::enter_test_mode::<T>(mode);
+    }
+
+    /// Describes the FIFO layout of the enabled endpoints, as the hardware uses it.
+    ///
+    /// Unlike [`MusbDriver::fifo_map`](crate::MusbDriver::fifo_map), the addresses are
+    /// those chosen when the endpoints were enabled.
+    pub fn fifo_map(&self) -> FifoMap {
+        alloc_endpoint::enabled_fifo_map(&self.ep_confs, |index, direction| {
+            let enabled = match direction {
+                Direction::In => EP_TX_ENABLED.load(Ordering::Acquire),
+                Direction::Out => EP_RX_ENABLED.load(Ordering::Acquire),
+            };
+            enabled & (1 << index) != 0
+        })
+    }
+
+    /// Detaches from the bus for [`Config::reconnect_detach_ms`] and attaches again,
+    /// forcing the host to reset and re-enumerate the device.
+    ///
+    /// This is needed e.g. by DFU detach. The device detaches right away and `poll`
+    /// attaches it again once the time is up. Returns `Unsupported` if no
+    /// [`Config::clock`] is configured or the core has no soft connect.
+    pub fn reconnect(&mut self) -> Result<(), Unsupported> {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "_lite")] {
+                Err(Unsupported)
+            } else {
+                let Some(clock) = self.config.clock else {
+                    warn!("musb/reconnect: no clock configured");
+                    return Err(Unsupported);
+                };
+                trace!("musb/reconnect: detaching for {} ms", self.config.reconnect_detach_ms);
+                common_impl::set_soft_connect::<T>(false);
+                self.reattach_at_ms = Some((clock.now_ms)() + self.config.reconnect_detach_ms as u64);
+                BUS_WAKER.wake();
+                Ok(())
+            }
+        }
     }
 }
 
@@ -26,20 +326,91 @@ impl<'d, T: MusbInstance> driver::Bus for Bus<'d, T> {
 
             let regs = T::regs();
 
-            // TODO: implement VBUS detection.
             if !self.inited {
                 self.init();
                 self.inited = true;
-                return Poll::Ready(Event::PowerDetected);
+            }
+
+            let power_present = self.vbus_present();
+            if power_present != self.power_present {
+                self.power_present = power_present;
+                // Keep the pull-up disconnected while there is no host to see it,
+                // `enable` connects it again.
+                #[cfg(not(feature = "_lite"))]
+                if !power_present {
+                    common_impl::set_soft_connect::<T>(false);
+                    self.reattach_at_ms = None;
+                }
+
+                trace!("musb/poll: vbus present: {}", power_present);
+                return Poll::Ready(if power_present {
+                    Event::PowerDetected
+                } else {
+                    Event::PowerRemoved
+                });
+            }
+
+            if !self.power_present {
+                return Poll::Pending;
+            }
+
+            #[cfg(not(feature = "_lite"))]
+            if let (Some(at_ms), Some(clock)) = (self.reattach_at_ms, self.config.clock) {
+                if (clock.now_ms)() >= at_ms {
+                    trace!("musb/poll: attaching again after reconnect");
+                    self.reattach_at_ms = None;
+                    common_impl::set_soft_connect::<T>(true);
+                } else {
+                    (clock.schedule_wake)(at_ms, cx.waker());
+                }
+            }
+
+            #[cfg(not(feature = "_lite"))]
+            if IRQ_L1_ENTER.load(Ordering::Acquire) {
+                IRQ_L1_ENTER.store(false, Ordering::Relaxed);
+                self.l1 = true;
+                self.l1_remote_wake = regs.lpm_attr().read().rmt_wak();
+                self.suspended = true;
+                trace!("musb/poll: L1 entered, remote wake: {}", self.l1_remote_wake);
+                return Poll::Ready(Event::Suspend);
+            }
+
+            #[cfg(not(feature = "_lite"))]
+            if IRQ_L1_EXIT.load(Ordering::Acquire) {
+                IRQ_L1_EXIT.store(false, Ordering::Relaxed);
+                if self.l1 {
+                    self.l1 = false;
+                    self.suspended = false;
+                    self.lpm_rearm();
+                    trace!("musb/poll: L1 exited");
+                    return Poll::Ready(Event::Resume);
+                }
             }
 
             if IRQ_RESUME.load(Ordering::Acquire) {
                 IRQ_RESUME.store(false, Ordering::Relaxed);
+                self.suspended = false;
+                #[cfg(not(feature = "_lite"))]
+                if self.l1 {
+                    self.l1 = false;
+                    self.lpm_rearm();
+                }
                 return Poll::Ready(Event::Resume);
             }
 
             if IRQ_RESET.load(Ordering::Acquire) {
                 IRQ_RESET.store(false, Ordering::Relaxed);
+                self.suspended = false;
+                REMOTE_WAKEUP_ENABLED.store(false, Ordering::Release);
+                #[cfg(not(feature = "_lite"))]
+                {
+                    self.l1 = false;
+                    self.lpm_rearm();
+                }
+
+                let high_speed = common_impl::is_high_speed::<T>();
+                HIGH_SPEED.store(high_speed, Ordering::Release);
+                trace!("musb/poll: negotiated high speed: {}", high_speed);
 
                 regs.index().write(|w| w.set_index(0));
                 #[cfg(not(feature = "_lite"))]
@@ -50,6 +421,17 @@ impl<'d, T: MusbInstance> driver::Bus for Bus<'d, T> {
                     regs.txcsrl().modify(|w| w.set_flush_fifo(true));
                     regs.txcsrl().modify(|w| w.set_flush_fifo(true));
                 }
+                // The host configures the device again, so endpoint tasks restart.
+                self.pending_in = 0;
+                self.pending_out = 0;
+                for index in 1..ENDPOINTS.len() {
+                    if EP_TX_ENABLED.load(Ordering::Acquire) & (1 << index) as u16 != 0 {
+                        self.disable_endpoint(index, Direction::In);
+                    }
+                    if EP_RX_ENABLED.load(Ordering::Acquire) & (1 << index) as u16 != 0 {
+                        self.disable_endpoint(index, Direction::Out);
+                    }
+                }
 
                 trace!("musb/poll: reset");
 
@@ -65,6 +447,8 @@ impl<'d, T: MusbInstance> driver::Bus for Bus<'d, T> {
 
             if IRQ_SUSPEND.load(Ordering::Acquire) {
                 IRQ_SUSPEND.store(false, Ordering::Relaxed);
+                self.suspended = true;
+                self.suspended_at_ms = self.config.clock.map_or(0, |clock| (clock.now_ms)());
                 return Poll::Ready(Event::Suspend);
             }
 
@@ -101,52 +485,84 @@ impl<'d, T: MusbInstance> driver::Bus for Bus<'d, T> {
         let ep_index = ep_addr.index();
 
         if enabled {
-            T::regs().index().write(|w| w.set_index(ep_index as u8));
-            match ep_addr.direction() {
-                Direction::Out => {
-                    common_impl::ep_rx_enable::<T>(ep_index as _, &self.ep_confs[ep_index]);
-
-                    let flags = EP_RX_ENABLED.load(Ordering::Acquire) | (1 << ep_index) as u16;
-                    EP_RX_ENABLED.store(flags, Ordering::Release);
-                    // Wake for `Endpoint::wait_enabled()`
-                    EP_RX_WAKERS[ep_index].wake();
-                }
-                Direction::In => {
-                    common_impl::ep_tx_enable::<T>(ep_index as _, &self.ep_confs[ep_index]);
-
-                    let flags = EP_TX_ENABLED.load(Ordering::Acquire) | (1 << ep_index) as u16;
-                    EP_TX_ENABLED.store(flags, Ordering::Release);
-                    // Wake for `Endpoint::wait_enabled()`
-                    EP_TX_WAKERS[ep_index].wake();
-                }
-            }
+            self.enable_endpoint(ep_index, ep_addr.direction());
         } else {
-            // py32 official CherryUsb port does nothing when disable an endpoint
-            match ep_addr.direction() {
-                Direction::Out => {
-                    let flags = EP_RX_ENABLED.load(Ordering::Acquire) & !((1 << ep_index) as u16);
-                    EP_RX_ENABLED.store(flags, Ordering::Release);
-                }
-                Direction::In => {
-                    let flags = EP_TX_ENABLED.load(Ordering::Acquire) & !((1 << ep_index) as u16);
-                    EP_TX_ENABLED.store(flags, Ordering::Release);
-                }
-            }
+            self.disable_endpoint(ep_index, ep_addr.direction());
         }
     }
 
     async fn enable(&mut self) {
+        trace!("musb/bus: enable");
         T::regs().faddr().write(|w| w.set_func_addr(0));
 
-        // T::regs().devctl().write(|w| {
-        //     w.set_session(true);
-        // });
-        // self.endpoint_set_enabled(EndpointAddress::from_parts(0, Direction::In), true);
-        // self.endpoint_set_enabled(EndpointAddress::from_parts(0, Direction::Out), true);
+        // Unmask the interrupts again in case the bus was disabled.
+        self.init();
+        self.inited = true;
+
+        #[cfg(not(feature = "_lite"))]
+        {
+            common_impl::set_soft_connect::<T>(true);
+            self.reattach_at_ms = None;
+        }
+    }
+
+    async fn disable(&mut self) {
+        trace!("musb/bus: disable");
+        common_impl::bus_disable::<T>();
+        #[cfg(not(feature = "_lite"))]
+        {
+            self.reattach_at_ms = None;
+        }
+    }
+
+    fn force_reset(&mut self) -> Result<(), Unsupported> {
+        // Does not block, `poll` attaches again after the detach time.
+        self.reconnect()
     }
-    async fn disable(&mut self) {}
 
     async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
-        Err(Unsupported)
+        // Leaving L1 is timed by the core.
+        #[cfg(not(feature = "_lite"))]
+        if self.l1 {
+            if !self.l1_remote_wake {
+                warn!("musb/remote_wakeup: L1 remote wake not allowed by host");
+                return Err(Unsupported);
+            }
+            trace!("musb/remote_wakeup: resuming from L1");
+            T::regs().lpm_cntrl().modify(|w| w.set_lpmres(true));
+            return Ok(());
+        }
+
+        let Some(clock) = self.config.clock else {
+            warn!("musb/remote_wakeup: no clock configured");
+            return Err(Unsupported);
+        };
+        if !self.suspended {
+            warn!("musb/remote_wakeup: bus is not suspended");
+            return Err(Unsupported);
+        }
+        if !REMOTE_WAKEUP_ENABLED.load(Ordering::Acquire) {
+            warn!("musb/remote_wakeup: not enabled by host");
+            return Err(Unsupported);
+        }
+
+        // USB 2.0 spec 7.1.7.7: the bus must have been idle for at least 5 ms. The
+        // suspend interrupt already comes after 3 ms, waiting 5 ms more is on the safe side.
+        wait_until_ms(clock, self.suspended_at_ms + REMOTE_WAKEUP_IDLE_MS).await;
+        if IRQ_RESUME.load(Ordering::Acquire) {
+            trace!("musb/remote_wakeup: host resumed first");
+            return Ok(());
+        }
+
+        trace!("musb/remote_wakeup: signalling resume");
+        // The device drives resume for at least 1 ms but no more than 15 ms.
+        let regs = T::regs();
+        regs.power().modify(|w| w.set_resume(true));
+        let resume_ms = self.config.resume_duration_ms.clamp(1, 15) as u64;
+        wait_until_ms(clock, (clock.now_ms)() + resume_ms).await;
+        regs.power().modify(|w| w.set_resume(false));
+
+        self.suspended = false;
+        Ok(())
     }
 }
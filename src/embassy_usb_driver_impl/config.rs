@@ -0,0 +1,232 @@
+use core::task::Waker;
+
+use crate::alloc_endpoint::{self, EndpointAllocError};
+
+/// Millisecond timer hooks, see [`Config::clock`].
+///
+/// Keeping them plain functions lets the HAL plug in any timer without tying the
+/// driver to an executor. With embassy-time, they map to `Instant::now()` and
+/// `embassy_time_driver::schedule_wake`.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    /// Returns a monotonic time in milliseconds.
+    pub now_ms: fn() -> u64,
+    /// Wakes the waker once `now_ms()` has reached the given time, e.g. from a
+    /// timer alarm.
+    pub schedule_wake: fn(u64, &Waker),
+}
+
+/// VBUS detection method.
+#[derive(Debug, Clone, Copy)]
+pub enum VbusDetect {
+    /// No VBUS detection, VBUS is always considered present.
+    ///
+    /// Suitable for bus-powered devices.
+    None,
+    /// Sample the DEVCTL `VBus` level comparators.
+    ///
+    /// VBUS is considered present when it is above the A-Valid threshold.
+    /// The level is re-evaluated whenever the bus is woken up, call
+    /// [`vbus_changed`](crate::vbus_changed) if your core does not raise an
+    /// interrupt on VBUS changes.
+    #[cfg(not(feature = "_lite"))]
+    Devctl,
+    /// Track VBUS with the session end (`Discon`) and session request (`Sess_Req`)
+    /// interrupts.
+    ///
+    /// The initial state is read from the DEVCTL `VBus` level, so `PowerDetected` is
+    /// only reported once a session is seen. VBUS is reported removed on session end
+    /// or while the level is below session end, and present again on session request
+    /// or once the level is back above session end.
+    #[cfg(not(feature = "_lite"))]
+    Interrupt,
+    /// Call a user function, e.g. reading a GPIO connected to VBUS.
+    ///
+    /// Call [`vbus_changed`](crate::vbus_changed) from the GPIO interrupt handler
+    /// so that the bus re-evaluates the VBUS state.
+    Callback(fn() -> bool),
+}
+
+/// How L1 (LPM sleep) requests from the host are answered.
+///
+/// The device must also advertise LPM support in the USB 2.0 extension
+/// capability of its BOS descriptor, otherwise the host never sends them.
+#[cfg(not(feature = "_lite"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LpmPolicy {
+    /// LPM transactions are not supported (`LPM_CNTRL.LPMEN` off).
+    Disabled,
+    /// LPM transactions are answered with NYET, the device stays in L0.
+    Nyet,
+    /// LPM transactions are answered with ACK and the device enters L1.
+    ///
+    /// L1 entry and exit are reported as `Suspend` and `Resume` events.
+    Accept,
+}
+
+/// What an OUT endpoint does with a received packet larger than the read buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OverflowPolicy {
+    /// Leave the packet in the FIFO and fail with `BufferOverflow`.
+    ///
+    /// [`Endpoint::peek_len`](crate::Endpoint::peek_len) returns its length, so the
+    /// read can be retried with a large enough buffer.
+    Keep,
+    /// Drop the packet and fail with `BufferOverflow`.
+    Discard,
+    /// Fill the buffer with the start of the packet and drop the rest.
+    ///
+    /// The read returns the number of bytes copied, the full packet length is
+    /// available from [`Endpoint::last_overflow`](crate::Endpoint::last_overflow).
+    Truncate,
+}
+
+/// MUSB driver configuration.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// VBUS detection method.
+    ///
+    /// Self-powered devices should use anything but [`VbusDetect::None`], so that
+    /// `PowerDetected`/`PowerRemoved` events reflect the cable state.
+    pub vbus_detect: VbusDetect,
+    /// Timer used to time bus signalling without blocking the executor.
+    ///
+    /// Remote wakeup and reconnecting are unsupported when this is `None`.
+    pub clock: Option<Clock>,
+    /// Duration of the resume signalling driven for remote wakeup, in milliseconds.
+    ///
+    /// The USB specification requires 1 to 15 ms, values out of range are clamped.
+    pub resume_duration_ms: u32,
+    /// How long [`Bus::reconnect`](crate::Bus::reconnect) keeps the device detached, in milliseconds.
+    pub reconnect_detach_ms: u32,
+    /// Maximum number of bulk packets per FIFO load.
+    ///
+    /// When the core supports bulk packet splitting (`CONFIGDATA.MPTxE`) or
+    /// amalgamation (`CONFIGDATA.MPRxE`), bulk endpoints are programmed with a MAXP
+    /// multiplier so that one FIFO load carries several max-size packets. The actual
+    /// multiplier is also limited by the endpoint FIFO size. `1` (default) disables it,
+    /// values are clamped to `1..=32`.
+    pub bulk_multiplier: u8,
+    /// Bulk IN endpoint indexes (bit `n` for endpoint `n`) using AutoSet.
+    ///
+    /// The core sets `TxPktRdy` by itself once a full packet has been written to the
+    /// FIFO, saving a register access per packet on streaming endpoints. Short packets
+    /// are still committed by the driver.
+    pub auto_set_endpoints: u16,
+    /// Bulk OUT endpoint indexes (bit `n` for endpoint `n`) using AutoClear.
+    ///
+    /// The core clears `RxPktRdy` by itself once a full packet has been read from the
+    /// FIFO. Short packets are still released by the driver.
+    pub auto_clear_endpoints: u16,
+    /// IN endpoint indexes (bit `n` for endpoint `n`) using double packet buffering.
+    ///
+    /// The endpoint FIFO holds two packets, so the next one can be written while the
+    /// previous is still on the wire. On dynamic FIFO cores twice the FIFO RAM is
+    /// reserved, on fixed FIFO cores it is only granted if the FIFO is large enough.
+    pub double_buffered_in_endpoints: u16,
+    /// OUT endpoint indexes (bit `n` for endpoint `n`) using double packet buffering.
+    pub double_buffered_out_endpoints: u16,
+    /// OUT endpoint indexes (bit `n` for endpoint `n`) using [`OverflowPolicy::Discard`].
+    ///
+    /// Endpoints in neither this nor [`overflow_truncate_endpoints`](Self::overflow_truncate_endpoints)
+    /// use [`OverflowPolicy::Keep`]. The control pipe always drops the packet and
+    /// stalls the transfer.
+    pub overflow_discard_endpoints: u16,
+    /// OUT endpoint indexes (bit `n` for endpoint `n`) using [`OverflowPolicy::Truncate`].
+    pub overflow_truncate_endpoints: u16,
+    /// Accept endpoints beyond the FIFO RAM at allocation time.
+    ///
+    /// Endpoint FIFOs are placed when the endpoints are enabled, so endpoints of
+    /// different alternate settings can share the RAM. An endpoint that does not fit
+    /// when enabled stays disabled until another endpoint releases RAM. By default
+    /// allocation fails once the RAM is exhausted.
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    pub overcommit_fifo: bool,
+    /// Accept both directions of a shared FIFO endpoint requested by address.
+    ///
+    /// Only one direction is enabled at a time, enabling the other one is deferred
+    /// until it is disabled, e.g. when switching alternate settings. By default the
+    /// second allocation fails. Endpoint plans grant this for endpoints marked with
+    /// an alternate setting.
+    #[cfg(feature = "_ep-shared-fifo")]
+    pub shared_fifo_direction_switching: bool,
+    /// Hold isochronous IN packets until the next SOF (`POWER.ISO_Update`).
+    ///
+    /// Without it, a packet is sent in response to the first IN token after it is
+    /// loaded, which may still be in the current frame.
+    pub iso_update: bool,
+    /// Make the control pipe `accept` wait until the status stage has completed.
+    ///
+    /// The control request handler then only returns once the host has seen the
+    /// ACK, e.g. before applying a new baud rate or rebooting into a bootloader.
+    pub await_status_stage: bool,
+    /// Max packet size of EP0: 8, 16, 32 or 64.
+    ///
+    /// It must match `max_packet_size_0` of the embassy-usb configuration, check it
+    /// with [`Config::validate`] before building the device.
+    pub control_max_packet_size: u16,
+    /// Request high-speed operation (`POWER.HS_Enab`).
+    ///
+    /// Only effective on cores with a high-speed PHY. The negotiated speed is
+    /// available from [`negotiated_speed`](crate::negotiated_speed) after each reset.
+    #[cfg(not(feature = "_lite"))]
+    pub high_speed: bool,
+    /// Link Power Management (L1 sleep) policy.
+    ///
+    /// Only enable this on cores implementing the LPM registers.
+    #[cfg(not(feature = "_lite"))]
+    pub lpm: LpmPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            vbus_detect: VbusDetect::None,
+            clock: None,
+            resume_duration_ms: 10,
+            reconnect_detach_ms: 50,
+            bulk_multiplier: 1,
+            auto_set_endpoints: 0,
+            auto_clear_endpoints: 0,
+            double_buffered_in_endpoints: 0,
+            double_buffered_out_endpoints: 0,
+            overflow_discard_endpoints: 0,
+            overflow_truncate_endpoints: 0,
+            #[cfg(not(feature = "_fixed-fifo-size"))]
+            overcommit_fifo: false,
+            #[cfg(feature = "_ep-shared-fifo")]
+            shared_fifo_direction_switching: false,
+            iso_update: false,
+            await_status_stage: false,
+            control_max_packet_size: 64,
+            #[cfg(not(feature = "_lite"))]
+            high_speed: false,
+            #[cfg(not(feature = "_lite"))]
+            lpm: LpmPolicy::Disabled,
+        }
+    }
+}
+
+impl Config {
+    /// Checks the configuration, as [`MusbDriver::start`](crate::MusbDriver::start)
+    /// cannot report errors.
+    pub fn validate(&self) -> Result<(), EndpointAllocError> {
+        if !alloc_endpoint::is_valid_control_max_packet_size(self.control_max_packet_size) {
+            return Err(EndpointAllocError::InvalidControlMaxPacketSize);
+        }
+        Ok(())
+    }
+
+    /// Overflow policy of OUT endpoint `index`.
+    pub(crate) fn overflow_policy(&self, index: usize) -> OverflowPolicy {
+        if self.overflow_truncate_endpoints & (1 << index) != 0 {
+            OverflowPolicy::Truncate
+        } else if self.overflow_discard_endpoints & (1 << index) != 0 {
+            OverflowPolicy::Discard
+        } else {
+            OverflowPolicy::Keep
+        }
+    }
+}
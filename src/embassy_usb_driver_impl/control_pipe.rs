@@ -1,11 +1,81 @@
 use super::*;
 
+/// Stage of the control transfer on EP0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) enum Ep0State {
+    /// Waiting for a SETUP packet.
+    Idle,
+    /// Data stage from device to host.
+    DataIn,
+    /// Data stage from host to device, or a request without data stage waiting
+    /// to be accepted.
+    DataOut,
+    /// `DataEnd` is set, the status stage completes with the next EP0 interrupt.
+    Status,
+}
+
 /// USB control pipe.
 pub struct ControlPipe<'d, T: MusbInstance> {
     pub(super) _phantom: PhantomData<&'d mut T>,
     pub(super) max_packet_size: u16,
-    pub(super) ep_in: Endpoint<'d, T, In>,
-    pub(super) ep_out: Endpoint<'d, T, Out>,
+    pub(super) state: Ep0State,
+    /// Whether `accept` waits for the status stage, see [`Config::await_status_stage`].
+    pub(super) await_status: bool,
+    /// DEVICE_REMOTE_WAKEUP state requested by the current SET/CLEAR_FEATURE,
+    /// applied once the stack accepts the request.
+    pub(super) remote_wakeup_request: Option<bool>,
+}
+
+/// The status stage of SET_ADDRESS must complete within 50 ms (USB 2.0 9.2.6.3).
+const SET_ADDRESS_TIMEOUT_FRAMES: u16 = 50;
+
+impl<'d, T: MusbInstance> ControlPipe<'d, T> {
+    /// Sets `DataEnd` (and `ServicedRxPktRdy`), the next EP0 interrupt then marks
+    /// the end of the status stage.
+    fn end_data_stage(&mut self) {
+        let regs = T::regs();
+        regs.index().write(|w| w.set_index(0));
+        IRQ_EP0.store(false, Ordering::SeqCst);
+        regs.csr0l().modify(|w| {
+            w.set_serviced_rx_pkt_rdy(true);
+            w.set_data_end(true);
+        });
+        self.state = Ep0State::Status;
+    }
+
+    /// Waits for the status stage of the current transfer to complete.
+    ///
+    /// Returns `false` if the transfer was aborted by a new SETUP or a bus reset.
+    async fn wait_status(&mut self) -> bool {
+        let regs = T::regs();
+        let completed = poll_fn(|cx| {
+            EP_TX_WAKERS[0].register(cx.waker());
+            if IRQ_RESET.load(Ordering::Acquire) {
+                return Poll::Ready(false);
+            }
+            if IRQ_EP0.swap(false, Ordering::SeqCst) {
+                regs.index().write(|w| w.set_index(0));
+                return Poll::Ready(!service_setup_end::<T>());
+            }
+            Poll::Pending
+        })
+        .await;
+
+        trace!("musb/control_pipe: status stage completed: {}", completed);
+        self.state = Ep0State::Idle;
+        completed
+    }
+
+    /// Acknowledges SET_FEATURE(TEST_MODE) and enters the test mode once its status
+    /// stage has completed.
+    #[cfg(not(feature = "_lite"))]
+    async fn enter_test_mode(&mut self, mode: TestMode) {
+        self.end_data_stage();
+        if self.wait_status().await {
+            common_impl::enter_test_mode::<T>(mode);
+        }
+    }
 }
 
 impl<'d, T: MusbInstance> driver::ControlPipe for ControlPipe<'d, T> {
@@ -16,10 +86,13 @@ impl<'d, T: MusbInstance> driver::ControlPipe for ControlPipe<'d, T> {
     async fn setup(&mut self) -> [u8; 8] {
         trace!("musb/control_pipe: setup");
         let regs = T::regs();
+        self.state = Ep0State::Idle;
         loop {
             poll_fn(|cx| {
                 EP_RX_WAKERS[0].register(cx.waker());
                 regs.index().write(|w| w.set_index(0));
+                // A transfer aborted before we got to its status stage.
+                service_setup_end::<T>();
                 if regs.csr0l().read().rx_pkt_rdy() {
                     Poll::Ready(())
                 } else {
@@ -35,10 +108,32 @@ impl<'d, T: MusbInstance> driver::ControlPipe for ControlPipe<'d, T> {
             }
 
             let mut buf = [0; 8];
-            (&mut buf)
-                .into_iter()
-                .for_each(|b| *b = regs.fifo(0).read().data());
+            common_impl::fifo_read::<T>(0, &mut buf);
+
+            // The data stage cannot be split into packets of an invalid size.
+            if !alloc_endpoint::is_valid_control_max_packet_size(self.max_packet_size) {
+                driver::ControlPipe::reject(self).await;
+                continue;
+            }
+
+            // Only high-speed devices have to support test modes, leave the request
+            // to the stack otherwise.
+            #[cfg(not(feature = "_lite"))]
+            if let Some(mode) = test_mode_request(&buf) {
+                if HIGH_SPEED.load(Ordering::Acquire) {
+                    self.enter_test_mode(mode).await;
+                    continue;
+                }
+            }
+
             regs.csr0l().modify(|w| w.set_serviced_rx_pkt_rdy(true));
+            self.state = if buf[0] & 0x80 != 0 {
+                Ep0State::DataIn
+            } else {
+                Ep0State::DataOut
+            };
+
+            self.remote_wakeup_request = remote_wakeup_request(&buf);
 
             trace!("musb/setup: read OK");
             return buf;
@@ -60,38 +155,37 @@ impl<'d, T: MusbInstance> driver::ControlPipe for ControlPipe<'d, T> {
 
         let regs = T::regs();
 
-        let _ = poll_fn(|cx| {
+        poll_fn(|cx| {
             EP_RX_WAKERS[0].register(cx.waker());
 
             regs.index().write(|w| w.set_index(0));
+            if service_setup_end::<T>() {
+                return Poll::Ready(Err(EndpointError::Disabled));
+            }
             let ready = regs.csr0l().read().rx_pkt_rdy();
             if ready {
-                Poll::Ready(())
+                Poll::Ready(Ok(()))
             } else {
                 Poll::Pending
             }
         })
-        .await;
+        .await?;
 
         regs.index().write(|w| w.set_index(0));
         let read_count = regs.count0().read().count();
-        if read_count as usize > buf.len() {
+        if read_count as usize > buf.len() || read_count as u16 > self.max_packet_size {
+            // Leaving the packet in the FIFO would wedge EP0, drop it and stall the transfer.
+            warn!("musb/control_pipe: dropped {} byte packet, buf.len() = {}", read_count, buf.len());
+            driver::ControlPipe::reject(self).await;
             return Err(EndpointError::BufferOverflow);
         }
 
-        if read_count as u16 > self.ep_out.info.max_packet_size {
-            return Err(EndpointError::BufferOverflow);
+        common_impl::fifo_read::<T>(0, &mut buf[..read_count as usize]);
+        if last {
+            self.end_data_stage();
+        } else {
+            regs.csr0l().modify(|w| w.set_serviced_rx_pkt_rdy(true));
         }
-
-        buf.into_iter()
-            .take(read_count as _)
-            .for_each(|b| *b = regs.fifo(0).read().data());
-        regs.csr0l().modify(|w| {
-            w.set_serviced_rx_pkt_rdy(true);
-            if last {
-                w.set_data_end(true);
-            }
-        });
         trace!("musb/control_pipe: READ OK, rx_len = {}", read_count);
 
         Ok(read_count as usize)
@@ -105,28 +199,34 @@ impl<'d, T: MusbInstance> driver::ControlPipe for ControlPipe<'d, T> {
             last
         );
 
-        if data.len() > self.ep_in.info.max_packet_size as usize {
+        if data.len() > self.max_packet_size as usize {
             return Err(EndpointError::BufferOverflow);
         }
 
         let regs = T::regs();
 
-        let _ = poll_fn(|cx| {
+        poll_fn(|cx| {
             EP_TX_WAKERS[0].register(cx.waker());
             regs.index().write(|w| w.set_index(0));
+            if service_setup_end::<T>() {
+                return Poll::Ready(Err(EndpointError::Disabled));
+            }
             let unready = regs.csr0l().read().tx_pkt_rdy();
             if unready {
                 Poll::Pending
             } else {
-                Poll::Ready(())
+                Poll::Ready(Ok(()))
             }
         })
-        .await;
+        .await?;
         regs.index().write(|w| w.set_index(0));
 
-        data.into_iter()
-            .for_each(|b| regs.fifo(0).write(|w| w.set_data(*b)));
+        common_impl::fifo_write::<T>(0, data);
 
+        if last {
+            IRQ_EP0.store(false, Ordering::SeqCst);
+            self.state = Ep0State::Status;
+        }
         regs.csr0l().modify(|w| {
             w.set_tx_pkt_rdy(true);
             if last {
@@ -139,15 +239,22 @@ impl<'d, T: MusbInstance> driver::ControlPipe for ControlPipe<'d, T> {
     async fn accept(&mut self) {
         trace!("musb/control_pipe: accept");
 
-        // Set `DataEnd` bit to indicate that no more data is expected
-        let regs = T::regs();
-        regs.index().write(|w| w.set_index(0));
-        regs.csr0l().modify(|w| w.set_data_end(true));
+        // Set `DataEnd` bit to indicate that no more data is expected, the last
+        // `data_out` already did for requests with a data stage.
+        if self.state != Ep0State::Status {
+            self.end_data_stage();
+        }
 
         // musb Programming Guide 21.1.2. WRITE REQUESTS
         // After this transfer completes, MUSB will generate an interrupt, but only
-        // INTRTX[0] (indicating EP0) is set. To prevent the state machine from getting
-        // stuck, we do not await this interrupt for now.
+        // INTRTX[0] (indicating EP0) is set.
+        if self.await_status && !self.wait_status().await {
+            warn!("musb/control_pipe: status stage aborted");
+            self.remote_wakeup_request = None;
+        }
+        if let Some(enabled) = self.remote_wakeup_request.take() {
+            REMOTE_WAKEUP_ENABLED.store(enabled, Ordering::Release);
+        }
     }
 
     async fn reject(&mut self) {
@@ -159,6 +266,8 @@ impl<'d, T: MusbInstance> driver::ControlPipe for ControlPipe<'d, T> {
             w.set_send_stall(true);
             w.set_serviced_rx_pkt_rdy(true);
         });
+        self.state = Ep0State::Idle;
+        self.remote_wakeup_request = None;
     }
 
     async fn accept_set_address(&mut self, addr: u8) {
@@ -166,21 +275,91 @@ impl<'d, T: MusbInstance> driver::ControlPipe for ControlPipe<'d, T> {
         // trace!("musb/control_pipe: setting addr: {}", addr);
         let regs = T::regs();
 
+        // The wait is bounded in frames, so keep the SOF interrupt waking us.
+        let _sof_irq = SofIrq::<T>::new();
+        EP0_SOF_WAIT.store(true, Ordering::Relaxed);
+        let start_frame = common_impl::frame_number::<T>();
+
         // We intentionally do not set the `DataEnd` so that the `SetupEnd` indicates the interrupt
         // after the Status Stage completes.
         // musb Programming Guide 21.1.1. ZERO DATA REQUESTS
-        let _ = poll_fn(|cx| {
+        let acked = poll_fn(|cx| {
             EP_TX_WAKERS[0].register(cx.waker());
+            if IRQ_RESET.load(Ordering::Acquire) {
+                return Poll::Ready(false);
+            }
             regs.index().write(|w| w.set_index(0));
             let setup_end = regs.csr0l().read().setup_end();
             if setup_end {
                 regs.csr0l().modify(|w| w.set_serviced_setup_end(true));
-                Poll::Ready(())
+                Poll::Ready(true)
+            } else if common_impl::frame_number::<T>().wrapping_sub(start_frame) & 0x7ff >= SET_ADDRESS_TIMEOUT_FRAMES {
+                warn!("musb/control_pipe: set address status stage timed out");
+                Poll::Ready(true)
             } else {
                 Poll::Pending
             }
         }).await;
+        EP0_SOF_WAIT.store(false, Ordering::Relaxed);
+        self.state = Ep0State::Idle;
+
+        if !acked {
+            trace!("musb/control_pipe: set address aborted by reset");
+            return;
+        }
         trace!("musb/control_pipe: set address acked, setting addr now");
         regs.faddr().write(|w| w.set_func_addr(addr));
     }
 }
+
+/// Services `CSR0.SetupEnd`, set when the host ended a control transfer before its
+/// data stage was complete, usually by sending a new SETUP. EP0 must be selected.
+///
+/// Returns whether the current transfer was aborted. Data left in the FIFO is
+/// flushed, unless it is the new SETUP packet.
+fn service_setup_end<T: MusbInstance>() -> bool {
+    let regs = T::regs();
+    let csr0l = regs.csr0l().read();
+    if !csr0l.setup_end() {
+        return false;
+    }
+
+    trace!("musb/control_pipe: setup end, new setup: {}", csr0l.rx_pkt_rdy());
+    regs.csr0l().modify(|w| w.set_serviced_setup_end(true));
+    #[cfg(not(feature = "_lite"))]
+    if !csr0l.rx_pkt_rdy() {
+        regs.csr0h().modify(|w| w.set_flush_fifo(true));
+    }
+    true
+}
+
+/// Returns the requested test mode if `setup` is SET_FEATURE(TEST_MODE).
+#[cfg(not(feature = "_lite"))]
+fn test_mode_request(setup: &[u8; 8]) -> Option<TestMode> {
+    const REQUEST_SET_FEATURE: u8 = 0x03;
+    const FEATURE_TEST_MODE: u8 = 0x02;
+
+    // Standard request, recipient device, the low byte of wIndex must be zero
+    if setup[0] != 0x00 || setup[1] != REQUEST_SET_FEATURE || setup[2] != FEATURE_TEST_MODE || setup[4] != 0 {
+        return None;
+    }
+    TestMode::from_selector(setup[5])
+}
+
+/// Decodes SET_FEATURE/CLEAR_FEATURE(DEVICE_REMOTE_WAKEUP), as the stack does not
+/// tell the driver whether the host allowed remote wakeup.
+fn remote_wakeup_request(setup: &[u8; 8]) -> Option<bool> {
+    const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+    const REQUEST_SET_FEATURE: u8 = 0x03;
+    const FEATURE_DEVICE_REMOTE_WAKEUP: u8 = 0x01;
+
+    // Standard request, recipient device
+    if setup[0] != 0x00 || setup[2] != FEATURE_DEVICE_REMOTE_WAKEUP {
+        return None;
+    }
+    match setup[1] {
+        REQUEST_SET_FEATURE => Some(true),
+        REQUEST_CLEAR_FEATURE => Some(false),
+        _ => None,
+    }
+}
@@ -1,5 +1,7 @@
 use super::*;
-use crate::alloc_endpoint::{self, EndpointConfig, EndpointData};
+use crate::alloc_endpoint::{self, AllocOptions, EndpointConfig, EndpointData, EndpointPlan, FifoMap, PlannedEndpoint};
+#[cfg(not(feature = "_fixed-fifo-size"))]
+use crate::alloc_endpoint::{ActiveFifos, FifoAllocator, FifoUsage};
 use crate::info::ENDPOINTS;
 use crate::assert_eq;
 
@@ -8,15 +10,29 @@ pub struct MusbDriver<'d, T: MusbInstance> {
     phantom: PhantomData<&'d mut T>,
     alloc: [EndpointData; ENDPOINTS.len()],
     #[cfg(not(feature = "_fixed-fifo-size"))]
-    next_fifo_addr_8bytes: u16,
+    fifo: FifoAllocator,
+    config: Config,
+    /// Endpoints handed out by `alloc_endpoint`, empty to allocate freely.
+    plan: &'static [PlannedEndpoint],
+    /// Planned endpoints already allocated, bit `n` for `plan[n]`.
+    plan_used: u32,
+}
+
+/// Fails the build for plans longer than the `plan_used` mask.
+struct PlanLen<const N: usize>;
+
+impl<const N: usize> PlanLen<N> {
+    const FITS_PLAN_USED: () = core::assert!(N <= 32, "endpoint plan: at most 32 endpoints");
 }
 
 impl<'d, T: MusbInstance> MusbDriver<'d, T> {
     /// Create a new USB driver.
     pub fn new() -> Self {
-        #[cfg(not(feature = "_fixed-fifo-size"))]
-        let next_fifo_addr_8bytes = 8; // Start after EP0's 64 bytes
-        
+        Self::new_with_config(Config::default())
+    }
+
+    /// Create a new USB driver with the given configuration.
+    pub fn new_with_config(config: Config) -> Self {
         let regs = T::regs();
         regs.index().write(|w| w.set_index(0));
 
@@ -30,6 +46,12 @@ impl<'d, T: MusbInstance> MusbDriver<'d, T> {
                     ep_type: EndpointType::Bulk,
                     tx_max_packet_size: 0,
                     rx_max_packet_size: 0,
+                    tx_packet_multiplier: 1,
+                    rx_packet_multiplier: 1,
+                    tx_auto_set: false,
+                    rx_auto_clear: false,
+                    tx_double_buffered: false,
+                    rx_double_buffered: false,
                     #[cfg(not(feature = "_fixed-fifo-size"))]
                     tx_fifo_size_bits: 0,
                     #[cfg(not(feature = "_fixed-fifo-size"))]
@@ -43,10 +65,47 @@ impl<'d, T: MusbInstance> MusbDriver<'d, T> {
                 used_rx: false,
             }; ENDPOINTS.len()],
             #[cfg(not(feature = "_fixed-fifo-size"))]
-            next_fifo_addr_8bytes,
+            fifo: FifoAllocator::new(),
+            config,
+            plan: &[],
+            plan_used: 0,
         }
     }
 
+    /// Create a new USB driver allocating endpoints from a validated plan.
+    ///
+    /// Endpoint allocations are matched against the plan by direction, type and
+    /// max packet size (and address, if requested), and use the planned address
+    /// and double buffering. Allocations not covered by the plan fail.
+    pub fn new_with_plan<const N: usize>(config: Config, plan: &'static EndpointPlan<N>) -> Self {
+        let () = PlanLen::<N>::FITS_PLAN_USED;
+        let mut driver = Self::new_with_config(config);
+        driver.plan = plan.endpoints();
+        driver
+    }
+
+    /// Takes the first unused planned endpoint matching an allocation request.
+    fn take_planned(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        direction: Direction,
+        max_packet_size: u16,
+    ) -> Option<PlannedEndpoint> {
+        let (n, planned) = self.plan.iter().enumerate().find(|(n, planned)| {
+            self.plan_used & (1 << n) == 0
+                && planned.direction() == direction
+                && planned.ep_type == ep_type
+                && planned.max_packet_size >= max_packet_size
+                && match ep_addr {
+                    Some(addr) => u8::from(addr) == planned.addr,
+                    None => true,
+                }
+        })?;
+        self.plan_used |= 1 << n;
+        Some(*planned)
+    }
+
     pub fn alloc_endpoint<D: Dir>(
         &mut self,
         ep_type: EndpointType,
@@ -66,18 +125,87 @@ impl<'d, T: MusbInstance> MusbDriver<'d, T> {
             assert_eq!(addr.direction(), D::dir(), "Wrong addr.direction");
         }
 
+        let max_packet_multiplier = if ep_type == EndpointType::Bulk && self.bulk_split_supported(D::dir()) {
+            self.config.bulk_multiplier.clamp(1, 32)
+        } else {
+            1
+        };
+
+        // Max packet sizes are checked against the fastest speed the device may run at.
+        #[cfg(feature = "_lite")]
+        let high_speed = false;
+        #[cfg(not(feature = "_lite"))]
+        let high_speed = self.config.high_speed;
+
+        let mut double_buffered_endpoints = match D::dir() {
+            Direction::Out => self.config.double_buffered_out_endpoints,
+            Direction::In => self.config.double_buffered_in_endpoints,
+        };
+
+        #[cfg(feature = "_ep-shared-fifo")]
+        let mut direction_switching = self.config.shared_fifo_direction_switching;
+
+        let mut ep_addr = ep_addr;
+        if !self.plan.is_empty() && ep_type != EndpointType::Control {
+            let Some(planned) = self.take_planned(ep_type, ep_addr, D::dir(), max_packet_size) else {
+                warn!("musb/alloc_ep: {:?} {:?} endpoint is not in the endpoint plan", ep_type, D::dir());
+                return Err(driver::EndpointAllocError);
+            };
+            ep_addr = Some(planned.addr.into());
+            double_buffered_endpoints = if planned.double_buffered {
+                1 << planned.index()
+            } else {
+                0
+            };
+            // The plan checked that both directions are never enabled together.
+            #[cfg(feature = "_ep-shared-fifo")]
+            {
+                direction_switching |= planned.alt_setting.is_some();
+            }
+        }
+
         let index = alloc_endpoint::alloc_endpoint(
             &mut self.alloc,
-            #[cfg(not(feature = "_fixed-fifo-size"))] &mut self.next_fifo_addr_8bytes,
+            #[cfg(not(feature = "_fixed-fifo-size"))] &mut self.fifo,
             ep_type,
             ep_addr.map(|addr| addr.index() as u8),
             D::dir(),
             max_packet_size,
+            AllocOptions {
+                max_packet_multiplier,
+                double_buffered_endpoints,
+                high_speed,
+                #[cfg(not(feature = "_fixed-fifo-size"))]
+                overcommit_fifo: self.config.overcommit_fifo,
+                #[cfg(feature = "_ep-shared-fifo")]
+                direction_switching,
+            },
         )
-        .map_err(|_| driver::EndpointAllocError)?;
+        .map_err(|e| {
+            warn!("musb/alloc_ep: failed to allocate {:?} {:?} endpoint: {:?}", ep_type, D::dir(), e);
+            driver::EndpointAllocError
+        })?;
+
+        let ep_conf = &mut self.alloc[index as usize].ep_conf;
+        let (packet_multiplier, auto_handshake) = match D::dir() {
+            Direction::Out => {
+                ep_conf.rx_auto_clear = ep_type == EndpointType::Bulk
+                    && self.config.auto_clear_endpoints & (1 << index) != 0;
+                (ep_conf.rx_packet_multiplier, ep_conf.rx_auto_clear)
+            }
+            Direction::In => {
+                ep_conf.tx_auto_set = ep_type == EndpointType::Bulk
+                    && self.config.auto_set_endpoints & (1 << index) != 0;
+                (ep_conf.tx_packet_multiplier, ep_conf.tx_auto_set)
+            }
+        };
 
         Ok(Endpoint {
             _phantom: PhantomData,
+            packet_multiplier,
+            auto_handshake,
+            overflow: self.config.overflow_policy(index as usize),
+            last_overflow: None,
             info: EndpointInfo {
                 addr: EndpointAddress::from_parts(index as usize, D::dir()),
                 ep_type,
@@ -87,16 +215,68 @@ impl<'d, T: MusbInstance> MusbDriver<'d, T> {
         })
     }
 
+    /// Describes the FIFO layout of the endpoints allocated so far.
+    ///
+    /// On dynamic FIFO cores, addresses are those assigned at allocation, the bus
+    /// may move a FIFO when it is enabled. [`Bus::fifo_map`](crate::Bus::fifo_map)
+    /// reports the layout in use.
+    pub fn fifo_map(&self) -> FifoMap {
+        alloc_endpoint::fifo_map(
+            &self.alloc,
+            #[cfg(not(feature = "_fixed-fifo-size"))] &self.fifo,
+        )
+    }
+
+    /// Reports the FIFO RAM usage and fragmentation of the endpoints allocated so far.
+    #[cfg(not(feature = "_fixed-fifo-size"))]
+    pub fn fifo_usage(&self) -> FifoUsage {
+        self.fifo.usage(&self.alloc)
+    }
+
+    /// Whether the core splits (IN) or amalgamates (OUT) bulk packets, see `CONFIGDATA`.
+    fn bulk_split_supported(&self, dir: Direction) -> bool {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "_lite")] {
+                let _ = dir;
+                false
+            } else {
+                let regs = T::regs();
+                regs.index().write(|w| w.set_index(0));
+                let configdata = regs.configdata().read();
+                match dir {
+                    Direction::In => configdata.mptxe(),
+                    Direction::Out => configdata.mprxe(),
+                }
+            }
+        }
+    }
+
     pub fn start(
         mut self,
         control_max_packet_size: u16,
     ) -> (crate::Bus<'d, T>, crate::ControlPipe<'d, T>) {
-        let ep_out = self
-            .alloc_endpoint(EndpointType::Control, Some(0x00.into()), control_max_packet_size, 0)
-            .unwrap();
-        let ep_in = self
-            .alloc_endpoint(EndpointType::Control, Some(0x80.into()), control_max_packet_size, 0)
-            .unwrap();
+        // `start` cannot fail, `Config::validate` reports invalid sizes up front.
+        // Substituting a size would make EP0 disagree with the bMaxPacketSize0
+        // advertised in the device descriptor, so the control pipe stalls instead.
+        if control_max_packet_size != self.config.control_max_packet_size {
+            warn!(
+                "musb driver: control max packet size {} differs from Config::control_max_packet_size {}",
+                control_max_packet_size,
+                self.config.control_max_packet_size
+            );
+        }
+        if alloc_endpoint::is_valid_control_max_packet_size(control_max_packet_size) {
+            self.alloc_endpoint::<Out>(EndpointType::Control, Some(0x00.into()), control_max_packet_size, 0)
+                .unwrap();
+            self.alloc_endpoint::<In>(EndpointType::Control, Some(0x80.into()), control_max_packet_size, 0)
+                .unwrap();
+        } else {
+            error!(
+                "musb driver: control max packet size {}: {:?}, stalling control requests",
+                control_max_packet_size,
+                alloc_endpoint::EndpointAllocError::InvalidControlMaxPacketSize
+            );
+        }
 
         trace!("musb driver: start");
 
@@ -104,6 +284,12 @@ impl<'d, T: MusbInstance> MusbDriver<'d, T> {
             ep_type: EndpointType::Bulk,
             tx_max_packet_size: 0,
             rx_max_packet_size: 0,
+            tx_packet_multiplier: 1,
+            rx_packet_multiplier: 1,
+            tx_auto_set: false,
+            rx_auto_clear: false,
+            tx_double_buffered: false,
+            rx_double_buffered: false,
             #[cfg(not(feature = "_fixed-fifo-size"))]
             tx_fifo_size_bits: 0,
             #[cfg(not(feature = "_fixed-fifo-size"))]
@@ -122,39 +308,60 @@ impl<'d, T: MusbInstance> MusbDriver<'d, T> {
             Bus {
                 phantom: PhantomData,
                 ep_confs,
+                #[cfg(not(feature = "_fixed-fifo-size"))]
+                fifo_active: ActiveFifos::default(),
+                pending_in: 0,
+                pending_out: 0,
                 inited: false,
+                config: self.config,
+                power_present: false,
+                suspended: false,
+                suspended_at_ms: 0,
+                #[cfg(not(feature = "_lite"))]
+                reattach_at_ms: None,
+                #[cfg(not(feature = "_lite"))]
+                l1: false,
+                #[cfg(not(feature = "_lite"))]
+                l1_remote_wake: false,
             },
             ControlPipe {
                 _phantom: PhantomData,
                 max_packet_size: control_max_packet_size,
-                ep_out,
-                ep_in,
+                state: control_pipe::Ep0State::Idle,
+                await_status: self.config.await_status_stage,
+                remote_wakeup_request: None,
             },
         )
     }
 }
 
-// impl<'d, T: MusbInstance> driver::Driver<'d> for Driver<'d, T> {
-//     type EndpointOut = Endpoint<'d, T, Out>;
-//     type EndpointIn = Endpoint<'d, T, In>;
-//     type ControlPipe = ControlPipe<'d, T>;
-//     type Bus = Bus<'d, T>;
-
-//     fn alloc_endpoint_in(
-//         &mut self,
-//         ep_type: EndpointType,
-//         max_packet_size: u16,
-//         interval_ms: u8,
-//     ) -> Result<Self::EndpointIn, driver::EndpointAllocError> {
-//         self.alloc_endpoint(ep_type, max_packet_size, interval_ms, false)
-//     }
-
-//     fn alloc_endpoint_out(
-//         &mut self,
-//         ep_type: EndpointType,
-//         max_packet_size: u16,
-//         interval_ms: u8,
-//     ) -> Result<Self::EndpointOut, driver::EndpointAllocError> {
-//         self.alloc_endpoint(ep_type, max_packet_size, interval_ms, false)
-//     }
-// }
+impl<'d, T: MusbInstance> driver::Driver<'d> for MusbDriver<'d, T> {
+    type EndpointOut = Endpoint<'d, T, Out>;
+    type EndpointIn = Endpoint<'d, T, In>;
+    type ControlPipe = ControlPipe<'d, T>;
+    type Bus = Bus<'d, T>;
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, driver::EndpointAllocError> {
+        self.alloc_endpoint(ep_type, ep_addr, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, driver::EndpointAllocError> {
+        self.alloc_endpoint(ep_type, ep_addr, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        MusbDriver::start(self, control_max_packet_size)
+    }
+}
@@ -3,9 +3,34 @@ use super::*;
 /// USB endpoint.
 pub struct Endpoint<'d, T: MusbInstance, D> {
     pub(super) _phantom: PhantomData<(&'d mut T, D)>,
+    /// Packets per FIFO load when the core splits/amalgamates bulk packets, 1 otherwise.
+    pub(super) packet_multiplier: u8,
+    /// AutoSet (IN) or AutoClear (OUT) is enabled, full FIFO loads need no handshake.
+    pub(super) auto_handshake: bool,
+    /// Handling of OUT packets larger than the read buffer.
+    pub(super) overflow: OverflowPolicy,
+    /// Length of the last received packet if it did not fit the read buffer.
+    pub(super) last_overflow: Option<usize>,
     pub(super) info: EndpointInfo,
 }
 
+impl<'d, T: MusbInstance, D> Endpoint<'d, T, D> {
+    /// Largest amount of data moved through the FIFO at once.
+    fn fifo_load_size(&self) -> usize {
+        self.info.max_packet_size as usize * self.packet_multiplier as usize
+    }
+
+    /// Whether the bus has this endpoint enabled.
+    fn is_enabled(&self) -> bool {
+        let index = self.info.addr.index();
+        let flags = match self.info.addr.direction() {
+            Direction::Out => EP_RX_ENABLED.load(Ordering::Acquire),
+            Direction::In => EP_TX_ENABLED.load(Ordering::Acquire),
+        };
+        flags & ((1 << index) as u16) != 0
+    }
+}
+
 // impl<'d, T: MusbInstance, > driver::Endpoint for Endpoint<'d, T, In> {
 impl<'d, T: MusbInstance, D: Dir> driver::Endpoint for Endpoint<'d, T, D> {
     fn info(&self) -> &EndpointInfo {
@@ -16,17 +41,11 @@ impl<'d, T: MusbInstance, D: Dir> driver::Endpoint for Endpoint<'d, T, D> {
         let _ = poll_fn(|cx| {
             let index = self.info.addr.index();
 
-            let enabled = match self.info.addr.direction() {
-                Direction::Out => {
-                    EP_RX_WAKERS[index].register(cx.waker());
-                    EP_RX_ENABLED.load(Ordering::Acquire) & ((1 << index) as u16) != 0
-                }
-                Direction::In => {
-                    EP_TX_WAKERS[index].register(cx.waker());
-                    EP_TX_ENABLED.load(Ordering::Acquire) & ((1 << index) as u16) != 0
-                }
-            };
-            if enabled {
+            match self.info.addr.direction() {
+                Direction::Out => EP_RX_WAKERS[index].register(cx.waker()),
+                Direction::In => EP_TX_WAKERS[index].register(cx.waker()),
+            }
+            if self.is_enabled() {
                 Poll::Ready(())
             } else {
                 Poll::Pending
@@ -37,43 +56,183 @@ impl<'d, T: MusbInstance, D: Dir> driver::Endpoint for Endpoint<'d, T, D> {
     }
 }
 
-impl<'d, T: MusbInstance> driver::EndpointOut for Endpoint<'d, T, Out> {
-    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+/// Isochronous transfer error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IsoError {
+    /// The endpoint failed as for any other transfer type.
+    Endpoint(EndpointError),
+    /// An IN token arrived before a packet was loaded, the frame was missed.
+    ///
+    /// The packet of the failing write is still queued for the next frame.
+    Underrun,
+    /// The received packet has a CRC or bit-stuffing error.
+    ///
+    /// Its data is still copied into the buffer, the value is its length.
+    DataError(usize),
+    /// A high-bandwidth packet was not completely received.
+    ///
+    /// Its data is still copied into the buffer, the value is its length.
+    Incomplete(usize),
+}
+
+impl<'d, T: MusbInstance> Endpoint<'d, T, Out> {
+    /// Overflow policy of this endpoint.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    /// Changes how packets larger than the read buffer are handled.
+    ///
+    /// The initial policy comes from [`Config::overflow_discard_endpoints`] and
+    /// [`Config::overflow_truncate_endpoints`].
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow = policy;
+    }
+
+    /// Length of the packet received by the last read, if it did not fit the buffer.
+    ///
+    /// Tells a read truncated under [`OverflowPolicy::Truncate`] from one that
+    /// exactly filled the buffer. Cleared by the next read.
+    pub fn last_overflow(&self) -> Option<usize> {
+        self.last_overflow
+    }
+
+    /// Length of the packet waiting in the FIFO, if any.
+    ///
+    /// After a read failed with `BufferOverflow` under [`OverflowPolicy::Keep`], this
+    /// is the buffer size needed to retry it.
+    pub fn peek_len(&self) -> Option<usize> {
+        let regs = T::regs();
+        regs.index().write(|w| w.set_index(self.info.addr.index() as _));
+        if regs.rxcsrl().read().rx_pkt_rdy() {
+            Some(regs.rxcount().read().count() as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Reads one isochronous packet, reporting CRC errors and incomplete packets.
+    ///
+    /// [`read`](driver::EndpointOut::read) also works on isochronous endpoints but
+    /// only logs these conditions.
+    pub async fn read_iso(&mut self, buf: &mut [u8]) -> Result<usize, IsoError> {
+        let (read_count, error) = self.read_packet(buf).await.map_err(IsoError::Endpoint)?;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(read_count),
+        }
+    }
+
+    /// Reads one packet, returning its length and the isochronous error it carries.
+    async fn read_packet(&mut self, buf: &mut [u8]) -> Result<(usize, Option<IsoError>), EndpointError> {
         trace!("musb/ep: read waiting, buf.len() = {}", buf.len());
         let index = self.info.addr.index();
         let regs = T::regs();
 
-        let _ = poll_fn(|cx| {
+        poll_fn(|cx| {
             EP_RX_WAKERS[index].register(cx.waker());
+            // Disabling wakes the endpoint, so a pending read ends here.
+            if !self.is_enabled() {
+                return Poll::Ready(Err(EndpointError::Disabled));
+            }
             regs.index().write(|w| w.set_index(index as _)); 
             let ready = regs.rxcsrl().read().rx_pkt_rdy();
             if ready {
-                Poll::Ready(())
+                Poll::Ready(Ok(()))
             } else {
                 Poll::Pending
             }
         })
-        .await;
+        .await?;
 
         regs.index().write(|w| w.set_index(index as _));
         let read_count = regs.rxcount().read().count();
+        self.last_overflow = None;
         if read_count as usize > buf.len() {
-            return Err(EndpointError::BufferOverflow);
+            self.last_overflow = Some(read_count as usize);
+            match self.overflow {
+                OverflowPolicy::Keep => return Err(EndpointError::BufferOverflow),
+                OverflowPolicy::Discard => {
+                    // Flushing drops only the oldest packet of a double-buffered FIFO.
+                    regs.rxcsrl().modify(|w| w.set_flush_fifo(true));
+                    warn!("musb/ep: dropped {} byte packet on endpoint {:#X}", read_count, self.info.addr);
+                    return Err(EndpointError::BufferOverflow);
+                }
+                OverflowPolicy::Truncate => {}
+            }
+        }
+        let copy_len = buf.len().min(read_count as usize);
+
+        // Both flags are cleared together with RxPktRdy.
+        let iso = self.info.ep_type == EndpointType::Isochronous;
+        let mut error = None;
+        if iso && regs.rxcsrl().read().data_error() {
+            error = Some(IsoError::DataError(copy_len));
+        }
+        #[cfg(not(feature = "_lite"))]
+        if iso && regs.rxcsrh().read().incomp_rx() {
+            error = Some(IsoError::Incomplete(copy_len));
         }
 
-        buf.into_iter()
-            .take(read_count as _)
-            .for_each(|b| *b = regs.fifo(index).read().data());
-        regs.rxcsrl().modify(|w| w.set_rx_pkt_rdy(false));
+        common_impl::fifo_read::<T>(index, &mut buf[..copy_len]);
+        if copy_len < read_count as usize {
+            // Drops the rest of the packet, AutoClear only fires once all of it was read.
+            regs.rxcsrl().modify(|w| w.set_flush_fifo(true));
+            warn!("musb/ep: truncated {} byte packet on endpoint {:#X}", read_count, self.info.addr);
+        } else if !(self.auto_handshake && read_count as usize == self.fifo_load_size()) {
+            // AutoClear already released a full FIFO load, clearing again could drop the next packet.
+            regs.rxcsrl().modify(|w| w.set_rx_pkt_rdy(false));
+        }
         trace!("musb/ep: read ok, rx_len = {}", read_count);
 
-        Ok(read_count as usize)
+        Ok((copy_len, error))
     }
 }
 
-impl<'d, T: MusbInstance> driver::EndpointIn for Endpoint<'d, T, In> {
-    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
-        if buf.len() > self.info.max_packet_size as usize {
+impl<'d, T: MusbInstance> driver::EndpointOut for Endpoint<'d, T, Out> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let (read_count, error) = self.read_packet(buf).await?;
+        if let Some(e) = error {
+            warn!("musb/ep: iso read on endpoint {:#X}: {:?}", self.info.addr, e);
+        }
+        Ok(read_count)
+    }
+
+    /// Unlike the default, reads whole FIFO loads and also ends once `buf` is full,
+    /// instead of failing the next read with `BufferOverflow`.
+    async fn read_transfer(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        trace!("musb/ep: read_transfer, buf.len() = {}", buf.len());
+        let fifo_load_size = self.fifo_load_size();
+
+        let mut n = 0;
+        loop {
+            let count = self.read(&mut buf[n..]).await?;
+            n += count;
+            // A short packet or a full buffer ends the transfer.
+            if count < fifo_load_size || n == buf.len() {
+                trace!("musb/ep: read_transfer ok, len = {}", n);
+                return Ok(n);
+            }
+        }
+    }
+}
+
+impl<'d, T: MusbInstance> Endpoint<'d, T, In> {
+    /// Queues one isochronous packet, reporting a missed frame as [`IsoError::Underrun`].
+    ///
+    /// The packet is sent in response to the next IN token, or after the next SOF
+    /// when [`Config::iso_update`] is set. It is not acknowledged by the host.
+    pub async fn write_iso(&mut self, buf: &[u8]) -> Result<(), IsoError> {
+        match self.write_packet(buf).await.map_err(IsoError::Endpoint)? {
+            true => Err(IsoError::Underrun),
+            false => Ok(()),
+        }
+    }
+
+    /// Loads one packet, returning whether an isochronous underrun was latched before it.
+    async fn write_packet(&mut self, buf: &[u8]) -> Result<bool, EndpointError> {
+        if buf.len() > self.fifo_load_size() {
             return Err(EndpointError::BufferOverflow);
         }
 
@@ -82,8 +241,13 @@ impl<'d, T: MusbInstance> driver::EndpointIn for Endpoint<'d, T, In> {
 
         trace!("musb/ep: write waiting len = {}", buf.len());
 
-        let _ = poll_fn(|cx| {
+        // With double packet buffering TxPktRdy clears as soon as one buffer is free,
+        // so the next packet is queued while the previous one is still on the wire.
+        poll_fn(|cx| {
             EP_TX_WAKERS[index].register(cx.waker());
+            if !self.is_enabled() {
+                return Poll::Ready(Err(EndpointError::Disabled));
+            }
             regs.index().write(|w| w.set_index(index as _));
 
             let unready = regs.txcsrl().read().tx_pkt_rdy();
@@ -91,17 +255,50 @@ impl<'d, T: MusbInstance> driver::EndpointIn for Endpoint<'d, T, In> {
             if unready {
                 Poll::Pending
             } else {
-                Poll::Ready(())
+                Poll::Ready(Ok(()))
             }
         })
-        .await;
+        .await?;
 
         regs.index().write(|w| w.set_index(index as _));
-        buf.into_iter()
-            .for_each(|b| regs.fifo(index).write(|w| w.set_data(*b)));
+        let underrun = self.info.ep_type == EndpointType::Isochronous && regs.txcsrl().read().under_run();
+        common_impl::fifo_write::<T>(index, buf);
 
-        regs.txcsrl().modify(|w| w.set_tx_pkt_rdy(true));
+        // AutoSet commits a full FIFO load by itself.
+        if !(self.auto_handshake && buf.len() == self.fifo_load_size()) {
+            regs.txcsrl().modify(|w| {
+                w.set_tx_pkt_rdy(true);
+                w.set_under_run(false);
+            });
+        }
         trace!("musb/ep: write ok");
+        Ok(underrun)
+    }
+}
+
+impl<'d, T: MusbInstance> driver::EndpointIn for Endpoint<'d, T, In> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if self.write_packet(buf).await? {
+            warn!("musb/ep: iso write on endpoint {:#X}: missed frame", self.info.addr);
+        }
+        Ok(())
+    }
+
+    /// Unlike the default, writes a whole FIFO load at a time, which the core splits into
+    /// max-size packets when bulk splitting is enabled.
+    async fn write_transfer(&mut self, buf: &[u8], needs_zlp: bool) -> Result<(), EndpointError> {
+        trace!("musb/ep: write_transfer len = {}, zlp = {}", buf.len(), needs_zlp);
+        let max_packet_size = self.info.max_packet_size as usize;
+
+        // The core splits each FIFO load into max-size packets if enabled.
+        for chunk in buf.chunks(self.fifo_load_size()) {
+            self.write(chunk).await?;
+        }
+        // A transfer ending on a full packet is only terminated by a ZLP.
+        let partial_len = buf.len() % max_packet_size;
+        if needs_zlp && partial_len == 0 {
+            self.write(&[]).await?;
+        }
         Ok(())
     }
 }
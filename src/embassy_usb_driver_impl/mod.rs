@@ -1,6 +1,6 @@
 use core::future::poll_fn;
 use core::marker::PhantomData;
-use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
 use core::task::Poll;
 
 use embassy_sync::waitqueue::AtomicWaker;
@@ -13,7 +13,7 @@ use crate::*;
 use crate::info::ENDPOINTS;
 
 mod endpoint;
-pub use endpoint::Endpoint;
+pub use endpoint::{Endpoint, IsoError};
 
 #[path = "driver.rs"]
 mod usb_driver;
@@ -25,9 +25,18 @@ pub use bus::Bus;
 mod control_pipe;
 pub use control_pipe::ControlPipe;
 
+mod config;
+pub use config::{Clock, Config, OverflowPolicy, VbusDetect};
+#[cfg(not(feature = "_lite"))]
+pub use config::LpmPolicy;
+
+#[cfg(not(feature = "_lite"))]
+pub use crate::common_impl::TestMode;
+
 const NEW_AW: AtomicWaker = AtomicWaker::new();
 
 static BUS_WAKER: AtomicWaker = NEW_AW;
+static SOF_WAKER: AtomicWaker = NEW_AW;
 
 static EP_TX_WAKERS: [AtomicWaker; ENDPOINTS.len()] = [NEW_AW; ENDPOINTS.len()];
 static EP_RX_WAKERS: [AtomicWaker; ENDPOINTS.len()] = [NEW_AW; ENDPOINTS.len()];
@@ -35,8 +44,102 @@ static EP_RX_WAKERS: [AtomicWaker; ENDPOINTS.len()] = [NEW_AW; ENDPOINTS.len()];
 static IRQ_RESET: AtomicBool = AtomicBool::new(false);
 static IRQ_SUSPEND: AtomicBool = AtomicBool::new(false);
 static IRQ_RESUME: AtomicBool = AtomicBool::new(false);
+static IRQ_SOF: AtomicBool = AtomicBool::new(false);
+// Number of `SofIrq` guards alive, the SOF interrupt is enabled while non-zero.
+static SOF_IRQ_USERS: AtomicU8 = AtomicU8::new(0);
+// Set while the control pipe times a status stage in frames.
+static EP0_SOF_WAIT: AtomicBool = AtomicBool::new(false);
+// Set on every EP0 interrupt, used to detect the end of a status stage.
+static IRQ_EP0: AtomicBool = AtomicBool::new(false);
+// Set when LPM is enabled, the LPM registers are not accessed otherwise.
+#[cfg(not(feature = "_lite"))]
+static LPM_ENABLED: AtomicBool = AtomicBool::new(false);
+#[cfg(not(feature = "_lite"))]
+static IRQ_L1_ENTER: AtomicBool = AtomicBool::new(false);
+#[cfg(not(feature = "_lite"))]
+static IRQ_L1_EXIT: AtomicBool = AtomicBool::new(false);
+// Speed read from `POWER.HS_Mode` on the last reset.
+static HIGH_SPEED: AtomicBool = AtomicBool::new(false);
 static EP_TX_ENABLED: AtomicU16 = AtomicU16::new(0);
 static EP_RX_ENABLED: AtomicU16 = AtomicU16::new(0);
+// Set when the host enables DEVICE_REMOTE_WAKEUP, tracked by the control pipe.
+static REMOTE_WAKEUP_ENABLED: AtomicBool = AtomicBool::new(false);
+// VBUS state reported by the session interrupts, used by `VbusDetect::Interrupt`.
+// Seeded from DEVCTL when the bus is initialized.
+#[cfg(not(feature = "_lite"))]
+static VBUS_SESSION: AtomicBool = AtomicBool::new(false);
+
+/// Notify the bus that the VBUS state may have changed.
+///
+/// Call this from the VBUS GPIO interrupt handler when using
+/// [`VbusDetect::Callback`], or periodically when using [`VbusDetect::Devctl`]
+/// on cores that do not raise an interrupt on VBUS changes.
+pub fn vbus_changed() {
+    BUS_WAKER.wake();
+}
+
+/// USB bus speed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UsbSpeed {
+    Full,
+    High,
+}
+
+/// Returns the speed negotiated during the last bus reset.
+///
+/// High speed is only negotiated when `Config::high_speed` is set on a capable core.
+pub fn negotiated_speed() -> UsbSpeed {
+    if HIGH_SPEED.load(Ordering::Acquire) {
+        UsbSpeed::High
+    } else {
+        UsbSpeed::Full
+    }
+}
+
+/// Returns the frame number of the last received SOF.
+pub fn frame_number<T: MusbInstance>() -> u16 {
+    common_impl::frame_number::<T>()
+}
+
+/// Keeps the SOF interrupt enabled while alive.
+struct SofIrq<T: MusbInstance>(PhantomData<T>);
+
+impl<T: MusbInstance> SofIrq<T> {
+    fn new() -> Self {
+        if SOF_IRQ_USERS.fetch_add(1, Ordering::SeqCst) == 0 {
+            T::regs().intrusbe().modify(|w| w.set_sof_enable(true));
+        }
+        Self(PhantomData)
+    }
+}
+
+impl<T: MusbInstance> Drop for SofIrq<T> {
+    fn drop(&mut self) {
+        if SOF_IRQ_USERS.fetch_sub(1, Ordering::SeqCst) == 1 {
+            T::regs().intrusbe().modify(|w| w.set_sof_enable(false));
+        }
+    }
+}
+
+/// Waits for the next SOF.
+///
+/// The SOF interrupt is only enabled while this future is alive. Only one task
+/// can wait at a time, a second waiter takes over the wakeup of the first.
+pub async fn wait_sof<T: MusbInstance>() {
+    IRQ_SOF.store(false, Ordering::SeqCst);
+    let _sof_irq = SofIrq::<T>::new();
+
+    poll_fn(|cx| {
+        SOF_WAKER.register(cx.waker());
+        if IRQ_SOF.swap(false, Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
 
 #[inline(always)]
 pub unsafe fn on_interrupt<T: MusbInstance>() {
@@ -49,6 +152,9 @@ pub unsafe fn on_interrupt<T: MusbInstance>() {
     if intrusb.reset() {
         IRQ_RESET.store(true, Ordering::SeqCst);
         BUS_WAKER.wake();
+        // Abort control transfers waiting on EP0.
+        EP_TX_WAKERS[0].wake();
+        EP_RX_WAKERS[0].wake();
     }
     if intrusb.suspend() {
         IRQ_SUSPEND.store(true, Ordering::SeqCst);
@@ -58,8 +164,38 @@ pub unsafe fn on_interrupt<T: MusbInstance>() {
         IRQ_RESUME.store(true, Ordering::SeqCst);
         BUS_WAKER.wake();
     }
+    #[cfg(not(feature = "_lite"))]
+    if LPM_ENABLED.load(Ordering::Relaxed) {
+        let lpm_intr = regs.lpm_intr().read();
+        if lpm_intr.lpmack() {
+            IRQ_L1_ENTER.store(true, Ordering::SeqCst);
+            BUS_WAKER.wake();
+        }
+        if lpm_intr.lpmres() {
+            IRQ_L1_EXIT.store(true, Ordering::SeqCst);
+            BUS_WAKER.wake();
+        }
+    }
+    if intrusb.sof() {
+        IRQ_SOF.store(true, Ordering::SeqCst);
+        SOF_WAKER.wake();
+        if EP0_SOF_WAIT.load(Ordering::Relaxed) {
+            EP_TX_WAKERS[0].wake();
+        }
+    }
+    #[cfg(not(feature = "_lite"))]
+    if intrusb.discon() {
+        VBUS_SESSION.store(false, Ordering::SeqCst);
+        BUS_WAKER.wake();
+    }
+    #[cfg(not(feature = "_lite"))]
+    if intrusb.sess_req() {
+        VBUS_SESSION.store(true, Ordering::SeqCst);
+        BUS_WAKER.wake();
+    }
     
     if intrtx.ep_tx(0) {
+        IRQ_EP0.store(true, Ordering::SeqCst);
         EP_TX_WAKERS[0].wake();
         EP_RX_WAKERS[0].wake();
     }
@@ -57,3 +57,23 @@ cfg_if::cfg_if! {
         include!("prebuilds/readconf/_generated.rs");
     }
 }
+
+#[cfg(all(test, any(feature = "builtin-std-8bep-2048", feature = "builtin-sf32lb52x")))]
+mod tests {
+    use super::regs::Usb;
+
+    /// Offsets from the Mentor MUSBMHDRC register map.
+    #[test]
+    fn register_offsets() {
+        let usb = unsafe { Usb::from_ptr(core::ptr::null_mut()) };
+        assert_eq!(usb.faddr().as_ptr() as usize, 0x00);
+        assert_eq!(usb.intrusbe().as_ptr() as usize, 0x0B);
+        assert_eq!(usb.index().as_ptr() as usize, 0x0E);
+        assert_eq!(usb.fifo(1).as_ptr() as usize, 0x24);
+        assert_eq!(usb.devctl().as_ptr() as usize, 0x60);
+        assert_eq!(usb.lpm_attr().as_ptr() as usize, 0x360);
+        assert_eq!(usb.lpm_cntrl().as_ptr() as usize, 0x362);
+        assert_eq!(usb.lpm_intren().as_ptr() as usize, 0x363);
+        assert_eq!(usb.lpm_intr().as_ptr() as usize, 0x364);
+    }
+}
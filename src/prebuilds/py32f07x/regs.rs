@@ -782,6 +782,18 @@ pub mod regs {
         pub const fn set_maxp(&mut self, val: u16) {
             self.0 = (self.0 & !(0x07ff << 0usize)) | (((val as u16) & 0x07ff) << 0usize);
         }
+        #[doc = "Packet multiplier minus one, for bulk split/amalgamation and high-bandwidth ISO"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn mult(&self) -> u8 {
+            let val = (self.0 >> 11usize) & 0x1f;
+            val as u8
+        }
+        #[doc = "Packet multiplier minus one, for bulk split/amalgamation and high-bandwidth ISO"]
+        #[inline(always)]
+        pub const fn set_mult(&mut self, val: u8) {
+            self.0 = (self.0 & !(0x1f << 11usize)) | (((val as u16) & 0x1f) << 11usize);
+        }
     }
     impl Default for Maxp {
         #[inline(always)]
@@ -791,13 +803,21 @@ pub mod regs {
     }
     impl core::fmt::Debug for Maxp {
         fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-            f.debug_struct("Maxp").field("maxp", &self.maxp()).finish()
+            f.debug_struct("Maxp")
+                .field("maxp", &self.maxp())
+                .field("mult", &self.mult())
+                .finish()
         }
     }
     #[cfg(feature = "defmt")]
     impl defmt::Format for Maxp {
         fn format(&self, f: defmt::Formatter) {
-            defmt::write!(f, "Maxp {{ maxp: {=u16:?} }}", self.maxp())
+            defmt::write!(
+                f,
+                "Maxp {{ maxp: {=u16:?}, mult: {=u8:?} }}",
+                self.maxp(),
+                self.mult()
+            )
         }
     }
     #[doc = "USB Power Control and Status Register"]
@@ -171,6 +171,26 @@ impl Usb {
     pub const fn rx_dpktbufdis(self) -> crate::common::Reg<regs::Dpktbufdis, crate::common::RW> {
         unsafe { crate::common::Reg::from_ptr(self.ptr.wrapping_add(0x0344usize) as _) }
     }
+    #[doc = "Attributes of the last LPM transaction received"]
+    #[inline(always)]
+    pub const fn lpm_attr(self) -> crate::common::Reg<regs::LpmAttr, crate::common::RW> {
+        unsafe { crate::common::Reg::from_ptr(self.ptr.wrapping_add(0x0360usize) as _) }
+    }
+    #[doc = "LPM control register"]
+    #[inline(always)]
+    pub const fn lpm_cntrl(self) -> crate::common::Reg<regs::LpmCntrl, crate::common::RW> {
+        unsafe { crate::common::Reg::from_ptr(self.ptr.wrapping_add(0x0362usize) as _) }
+    }
+    #[doc = "LPM interrupt enable register"]
+    #[inline(always)]
+    pub const fn lpm_intren(self) -> crate::common::Reg<regs::LpmIntren, crate::common::RW> {
+        unsafe { crate::common::Reg::from_ptr(self.ptr.wrapping_add(0x0363usize) as _) }
+    }
+    #[doc = "LPM interrupt status register"]
+    #[inline(always)]
+    pub const fn lpm_intr(self) -> crate::common::Reg<regs::LpmIntr, crate::common::RW> {
+        unsafe { crate::common::Reg::from_ptr(self.ptr.wrapping_add(0x0364usize) as _) }
+    }
 }
 pub mod regs {
     #[doc = "Core configuration information register"]
@@ -1405,6 +1425,362 @@ pub mod regs {
             defmt :: write ! (f , "Intrusbe {{ suspend_enable: {=bool:?}, resume_enable: {=bool:?}, reset_enable: {=bool:?}, sof_enable: {=bool:?}, conn_enable: {=bool:?}, discon_enable: {=bool:?}, sess_req_enable: {=bool:?}, vbus_error_enable: {=bool:?} }}" , self . suspend_enable () , self . resume_enable () , self . reset_enable () , self . sof_enable () , self . conn_enable () , self . discon_enable () , self . sess_req_enable () , self . vbus_error_enable ())
         }
     }
+    #[doc = "Attributes of the last LPM transaction received"]
+    #[repr(transparent)]
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    pub struct LpmAttr(pub u16);
+    impl LpmAttr {
+        #[doc = "Requested link state, 0x1 for Sleep (L1)"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn link_state(&self) -> u8 {
+            let val = (self.0 >> 0usize) & 0x0f;
+            val as u8
+        }
+        #[doc = "Requested link state, 0x1 for Sleep (L1)"]
+        #[inline(always)]
+        pub const fn set_link_state(&mut self, val: u8) {
+            self.0 = (self.0 & !(0x0f << 0usize)) | (((val as u16) & 0x0f) << 0usize);
+        }
+        #[doc = "Host Initiated Resume Duration"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn hird(&self) -> u8 {
+            let val = (self.0 >> 4usize) & 0x0f;
+            val as u8
+        }
+        #[doc = "Host Initiated Resume Duration"]
+        #[inline(always)]
+        pub const fn set_hird(&mut self, val: u8) {
+            self.0 = (self.0 & !(0x0f << 4usize)) | (((val as u16) & 0x0f) << 4usize);
+        }
+        #[doc = "Remote wakeup is enabled by the host for this L1 state"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn rmt_wak(&self) -> bool {
+            let val = (self.0 >> 8usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Remote wakeup is enabled by the host for this L1 state"]
+        #[inline(always)]
+        pub const fn set_rmt_wak(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 8usize)) | (((val as u16) & 0x01) << 8usize);
+        }
+        #[doc = "Endpoint the LPM transaction was addressed to"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn end_pnt(&self) -> u8 {
+            let val = (self.0 >> 12usize) & 0x0f;
+            val as u8
+        }
+        #[doc = "Endpoint the LPM transaction was addressed to"]
+        #[inline(always)]
+        pub const fn set_end_pnt(&mut self, val: u8) {
+            self.0 = (self.0 & !(0x0f << 12usize)) | (((val as u16) & 0x0f) << 12usize);
+        }
+    }
+    impl Default for LpmAttr {
+        #[inline(always)]
+        fn default() -> LpmAttr {
+            LpmAttr(0)
+        }
+    }
+    impl core::fmt::Debug for LpmAttr {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.debug_struct("LpmAttr")
+                .field("link_state", &self.link_state())
+                .field("hird", &self.hird())
+                .field("rmt_wak", &self.rmt_wak())
+                .field("end_pnt", &self.end_pnt())
+                .finish()
+        }
+    }
+    #[cfg(feature = "defmt")]
+    impl defmt::Format for LpmAttr {
+        fn format(&self, f: defmt::Formatter) {
+            defmt :: write ! (f , "LpmAttr {{ link_state: {=u8:?}, hird: {=u8:?}, rmt_wak: {=bool:?}, end_pnt: {=u8:?} }}" , self . link_state () , self . hird () , self . rmt_wak () , self . end_pnt ())
+        }
+    }
+    #[doc = "LPM control register"]
+    #[repr(transparent)]
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    pub struct LpmCntrl(pub u8);
+    impl LpmCntrl {
+        #[doc = "Respond to the next LPM transaction with ACK instead of NYET"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmxmt(&self) -> bool {
+            let val = (self.0 >> 0usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Respond to the next LPM transaction with ACK instead of NYET"]
+        #[inline(always)]
+        pub const fn set_lpmxmt(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 0usize)) | (((val as u8) & 0x01) << 0usize);
+        }
+        #[doc = "Drive resume signalling to leave L1 (device-initiated remote wake)"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmres(&self) -> bool {
+            let val = (self.0 >> 1usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Drive resume signalling to leave L1 (device-initiated remote wake)"]
+        #[inline(always)]
+        pub const fn set_lpmres(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 1usize)) | (((val as u8) & 0x01) << 1usize);
+        }
+        #[doc = "LPM and extended transaction support"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmen(&self) -> super::vals::LpmSupport {
+            let val = (self.0 >> 2usize) & 0x03;
+            super::vals::LpmSupport::from_bits(val as u8)
+        }
+        #[doc = "LPM and extended transaction support"]
+        #[inline(always)]
+        pub const fn set_lpmen(&mut self, val: super::vals::LpmSupport) {
+            self.0 = (self.0 & !(0x03 << 2usize)) | (((val.to_bits() as u8) & 0x03) << 2usize);
+        }
+        #[doc = "NAK all transactions except LPM while in L1"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmnak(&self) -> bool {
+            let val = (self.0 >> 4usize) & 0x01;
+            val != 0
+        }
+        #[doc = "NAK all transactions except LPM while in L1"]
+        #[inline(always)]
+        pub const fn set_lpmnak(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 4usize)) | (((val as u8) & 0x01) << 4usize);
+        }
+    }
+    impl Default for LpmCntrl {
+        #[inline(always)]
+        fn default() -> LpmCntrl {
+            LpmCntrl(0)
+        }
+    }
+    impl core::fmt::Debug for LpmCntrl {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.debug_struct("LpmCntrl")
+                .field("lpmxmt", &self.lpmxmt())
+                .field("lpmres", &self.lpmres())
+                .field("lpmen", &self.lpmen())
+                .field("lpmnak", &self.lpmnak())
+                .finish()
+        }
+    }
+    #[cfg(feature = "defmt")]
+    impl defmt::Format for LpmCntrl {
+        fn format(&self, f: defmt::Formatter) {
+            defmt :: write ! (f , "LpmCntrl {{ lpmxmt: {=bool:?}, lpmres: {=bool:?}, lpmen: {:?}, lpmnak: {=bool:?} }}" , self . lpmxmt () , self . lpmres () , self . lpmen () , self . lpmnak ())
+        }
+    }
+    #[doc = "LPM interrupt status register, cleared on read"]
+    #[repr(transparent)]
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    pub struct LpmIntr(pub u8);
+    impl LpmIntr {
+        #[doc = "An LPM transaction was answered with STALL"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmst(&self) -> bool {
+            let val = (self.0 >> 0usize) & 0x01;
+            val != 0
+        }
+        #[doc = "An LPM transaction was answered with STALL"]
+        #[inline(always)]
+        pub const fn set_lpmst(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 0usize)) | (((val as u8) & 0x01) << 0usize);
+        }
+        #[doc = "An LPM transaction was answered with NYET"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmny(&self) -> bool {
+            let val = (self.0 >> 1usize) & 0x01;
+            val != 0
+        }
+        #[doc = "An LPM transaction was answered with NYET"]
+        #[inline(always)]
+        pub const fn set_lpmny(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 1usize)) | (((val as u8) & 0x01) << 1usize);
+        }
+        #[doc = "An LPM transaction was answered with ACK, the core entered L1"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmack(&self) -> bool {
+            let val = (self.0 >> 2usize) & 0x01;
+            val != 0
+        }
+        #[doc = "An LPM transaction was answered with ACK, the core entered L1"]
+        #[inline(always)]
+        pub const fn set_lpmack(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 2usize)) | (((val as u8) & 0x01) << 2usize);
+        }
+        #[doc = "An LPM transaction was answered with NYET because data was pending"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmnc(&self) -> bool {
+            let val = (self.0 >> 3usize) & 0x01;
+            val != 0
+        }
+        #[doc = "An LPM transaction was answered with NYET because data was pending"]
+        #[inline(always)]
+        pub const fn set_lpmnc(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 3usize)) | (((val as u8) & 0x01) << 3usize);
+        }
+        #[doc = "Resume signalling completed, the core left L1"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmres(&self) -> bool {
+            let val = (self.0 >> 4usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Resume signalling completed, the core left L1"]
+        #[inline(always)]
+        pub const fn set_lpmres(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 4usize)) | (((val as u8) & 0x01) << 4usize);
+        }
+        #[doc = "An LPM transaction requested an unsupported link state"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmerr(&self) -> bool {
+            let val = (self.0 >> 5usize) & 0x01;
+            val != 0
+        }
+        #[doc = "An LPM transaction requested an unsupported link state"]
+        #[inline(always)]
+        pub const fn set_lpmerr(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 5usize)) | (((val as u8) & 0x01) << 5usize);
+        }
+    }
+    impl Default for LpmIntr {
+        #[inline(always)]
+        fn default() -> LpmIntr {
+            LpmIntr(0)
+        }
+    }
+    impl core::fmt::Debug for LpmIntr {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.debug_struct("LpmIntr")
+                .field("lpmst", &self.lpmst())
+                .field("lpmny", &self.lpmny())
+                .field("lpmack", &self.lpmack())
+                .field("lpmnc", &self.lpmnc())
+                .field("lpmres", &self.lpmres())
+                .field("lpmerr", &self.lpmerr())
+                .finish()
+        }
+    }
+    #[cfg(feature = "defmt")]
+    impl defmt::Format for LpmIntr {
+        fn format(&self, f: defmt::Formatter) {
+            defmt :: write ! (f , "LpmIntr {{ lpmst: {=bool:?}, lpmny: {=bool:?}, lpmack: {=bool:?}, lpmnc: {=bool:?}, lpmres: {=bool:?}, lpmerr: {=bool:?} }}" , self . lpmst () , self . lpmny () , self . lpmack () , self . lpmnc () , self . lpmres () , self . lpmerr ())
+        }
+    }
+    #[doc = "LPM interrupt enable register"]
+    #[repr(transparent)]
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    pub struct LpmIntren(pub u8);
+    impl LpmIntren {
+        #[doc = "Enable the LPMST interrupt"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmsten(&self) -> bool {
+            let val = (self.0 >> 0usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Enable the LPMST interrupt"]
+        #[inline(always)]
+        pub const fn set_lpmsten(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 0usize)) | (((val as u8) & 0x01) << 0usize);
+        }
+        #[doc = "Enable the LPMNY interrupt"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmnyen(&self) -> bool {
+            let val = (self.0 >> 1usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Enable the LPMNY interrupt"]
+        #[inline(always)]
+        pub const fn set_lpmnyen(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 1usize)) | (((val as u8) & 0x01) << 1usize);
+        }
+        #[doc = "Enable the LPMACK interrupt"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmacken(&self) -> bool {
+            let val = (self.0 >> 2usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Enable the LPMACK interrupt"]
+        #[inline(always)]
+        pub const fn set_lpmacken(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 2usize)) | (((val as u8) & 0x01) << 2usize);
+        }
+        #[doc = "Enable the LPMNC interrupt"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmncen(&self) -> bool {
+            let val = (self.0 >> 3usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Enable the LPMNC interrupt"]
+        #[inline(always)]
+        pub const fn set_lpmncen(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 3usize)) | (((val as u8) & 0x01) << 3usize);
+        }
+        #[doc = "Enable the LPMRES interrupt"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmresen(&self) -> bool {
+            let val = (self.0 >> 4usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Enable the LPMRES interrupt"]
+        #[inline(always)]
+        pub const fn set_lpmresen(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 4usize)) | (((val as u8) & 0x01) << 4usize);
+        }
+        #[doc = "Enable the LPMERR interrupt"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn lpmerren(&self) -> bool {
+            let val = (self.0 >> 5usize) & 0x01;
+            val != 0
+        }
+        #[doc = "Enable the LPMERR interrupt"]
+        #[inline(always)]
+        pub const fn set_lpmerren(&mut self, val: bool) {
+            self.0 = (self.0 & !(0x01 << 5usize)) | (((val as u8) & 0x01) << 5usize);
+        }
+    }
+    impl Default for LpmIntren {
+        #[inline(always)]
+        fn default() -> LpmIntren {
+            LpmIntren(0)
+        }
+    }
+    impl core::fmt::Debug for LpmIntren {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.debug_struct("LpmIntren")
+                .field("lpmsten", &self.lpmsten())
+                .field("lpmnyen", &self.lpmnyen())
+                .field("lpmacken", &self.lpmacken())
+                .field("lpmncen", &self.lpmncen())
+                .field("lpmresen", &self.lpmresen())
+                .field("lpmerren", &self.lpmerren())
+                .finish()
+        }
+    }
+    #[cfg(feature = "defmt")]
+    impl defmt::Format for LpmIntren {
+        fn format(&self, f: defmt::Formatter) {
+            defmt :: write ! (f , "LpmIntren {{ lpmsten: {=bool:?}, lpmnyen: {=bool:?}, lpmacken: {=bool:?}, lpmncen: {=bool:?}, lpmresen: {=bool:?}, lpmerren: {=bool:?} }}" , self . lpmsten () , self . lpmnyen () , self . lpmacken () , self . lpmncen () , self . lpmresen () , self . lpmerren ())
+        }
+    }
     #[doc = "Maximum payload size forendpoint"]
     #[repr(transparent)]
     #[derive(Copy, Clone, Eq, PartialEq)]
@@ -1422,6 +1798,18 @@ pub mod regs {
         pub const fn set_maxp(&mut self, val: u16) {
             self.0 = (self.0 & !(0x07ff << 0usize)) | (((val as u16) & 0x07ff) << 0usize);
         }
+        #[doc = "Packet multiplier minus one, for bulk split/amalgamation and high-bandwidth ISO"]
+        #[must_use]
+        #[inline(always)]
+        pub const fn mult(&self) -> u8 {
+            let val = (self.0 >> 11usize) & 0x1f;
+            val as u8
+        }
+        #[doc = "Packet multiplier minus one, for bulk split/amalgamation and high-bandwidth ISO"]
+        #[inline(always)]
+        pub const fn set_mult(&mut self, val: u8) {
+            self.0 = (self.0 & !(0x1f << 11usize)) | (((val as u16) & 0x1f) << 11usize);
+        }
     }
     impl Default for Maxp {
         #[inline(always)]
@@ -1431,13 +1819,21 @@ pub mod regs {
     }
     impl core::fmt::Debug for Maxp {
         fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-            f.debug_struct("Maxp").field("maxp", &self.maxp()).finish()
+            f.debug_struct("Maxp")
+                .field("maxp", &self.maxp())
+                .field("mult", &self.mult())
+                .finish()
         }
     }
     #[cfg(feature = "defmt")]
     impl defmt::Format for Maxp {
         fn format(&self, f: defmt::Formatter) {
-            defmt::write!(f, "Maxp {{ maxp: {=u16:?} }}", self.maxp())
+            defmt::write!(
+                f,
+                "Maxp {{ maxp: {=u16:?}, mult: {=u8:?} }}",
+                self.maxp(),
+                self.mult()
+            )
         }
     }
     #[doc = "USB Power Control and Status Register"]
@@ -2343,6 +2739,41 @@ pub mod vals {
     #[repr(u8)]
     #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub enum LpmSupport {
+        #[doc = "LPM and extended transactions not supported"]
+        None = 0x0,
+        #[doc = "Extended transactions supported, LPM transactions not supported"]
+        Extended = 0x01,
+        #[doc = "LPM and extended transactions not supported"]
+        NoneAlt = 0x02,
+        #[doc = "LPM and extended transactions supported"]
+        LpmExtended = 0x03,
+    }
+    impl LpmSupport {
+        #[inline(always)]
+        pub const fn from_bits(val: u8) -> LpmSupport {
+            unsafe { core::mem::transmute(val & 0x03) }
+        }
+        #[inline(always)]
+        pub const fn to_bits(self) -> u8 {
+            unsafe { core::mem::transmute(self) }
+        }
+    }
+    impl From<u8> for LpmSupport {
+        #[inline(always)]
+        fn from(val: u8) -> LpmSupport {
+            LpmSupport::from_bits(val)
+        }
+    }
+    impl From<LpmSupport> for u8 {
+        #[inline(always)]
+        fn from(val: LpmSupport) -> u8 {
+            LpmSupport::to_bits(val)
+        }
+    }
+    #[repr(u8)]
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     pub enum UtmiWidth {
         EightBit = 0x0,
         SixteenBit = 0x01,
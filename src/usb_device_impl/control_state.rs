@@ -30,6 +30,7 @@ impl From<u8> for ControlStateEnum {
 pub(super) struct ControlState {
     state: AtomicU8,
     tx_len: AtomicU32,
+    rx_len: AtomicU32,
 }
 
 impl ControlState {
@@ -37,6 +38,7 @@ impl ControlState {
         Self {
             state: AtomicU8::new(ControlStateEnum::Idle as u8),
             tx_len: AtomicU32::new(0),
+            rx_len: AtomicU32::new(0),
         }
     }
 
@@ -69,4 +71,26 @@ impl ControlState {
     pub(super) fn get_tx_len(&self) -> u32 {
         self.tx_len.load(Ordering::SeqCst)
     }
+
+    pub(super) fn reset_rx_len(&self) {
+        self.rx_len.store(0, Ordering::SeqCst);
+    }
+
+    pub(super) fn set_rx_len(&self, rx_len: u32) {
+        self.rx_len.store(rx_len, Ordering::SeqCst);
+    }
+
+    pub(super) fn decrease_rx_len(&self, len: u32) {
+        let rx_len = self.rx_len.load(Ordering::SeqCst);
+        if len > rx_len {
+            warn!("decrease_rx_len: len {} > rx_len {}", len, rx_len);
+            self.rx_len.store(0, Ordering::SeqCst);
+        } else {
+            self.rx_len.store(rx_len - len, Ordering::SeqCst);
+        }
+    }
+
+    pub(super) fn get_rx_len(&self) -> u32 {
+        self.rx_len.load(Ordering::SeqCst)
+    }
 }
@@ -21,7 +21,7 @@ use embassy_usb_driver::{Direction, EndpointType};
 use usb_device::bus::PollResult;
 use usb_device::{UsbDirection, UsbError};
 
-use crate::alloc_endpoint::{self, EndpointAllocError, EndpointConfig, EndpointData};
+use crate::alloc_endpoint::{self, AllocOptions, EndpointAllocError, EndpointConfig, EndpointData, FifoMap};
 use crate::common_impl;
 use crate::{trace, warn};
 use crate::MusbInstance;
@@ -48,6 +48,12 @@ impl<T: MusbInstance> UsbdBus<T> {
                     ep_type: EndpointType::Bulk,
                     tx_max_packet_size: 0,
                     rx_max_packet_size: 0,
+                    tx_packet_multiplier: 1,
+                    rx_packet_multiplier: 1,
+                    tx_auto_set: false,
+                    rx_auto_clear: false,
+                    tx_double_buffered: false,
+                    rx_double_buffered: false,
                     #[cfg(not(feature = "_fixed-fifo-size"))]
                     tx_fifo_size_bits: 0,
                     #[cfg(not(feature = "_fixed-fifo-size"))]
@@ -63,6 +69,11 @@ impl<T: MusbInstance> UsbdBus<T> {
             control_state: ControlState::new(),
         }
     }
+
+    /// Describes the FIFO layout of the endpoints allocated so far.
+    pub fn fifo_map(&self) -> FifoMap {
+        alloc_endpoint::fifo_map(&self.endpoints)
+    }
 }
 
 impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
@@ -86,15 +97,22 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
             usb_device::UsbDirection::Out => Direction::Out,
         };
 
-        alloc_endpoint::alloc_endpoint(&mut self.endpoints, ep_type, index, dir, max_packet_size)
-            .map_err(|e| match e {
-                EndpointAllocError::EndpointOverflow => UsbError::EndpointOverflow,
-                EndpointAllocError::InvalidEndpoint => UsbError::InvalidEndpoint,
-                #[cfg(not(feature = "_fixed-fifo-size"))]
-                EndpointAllocError::BufferOverflow => UsbError::EndpointMemoryOverflow,
-                EndpointAllocError::EpDirNotSupported => UsbError::InvalidEndpoint,
-                EndpointAllocError::EpUsed => UsbError::InvalidEndpoint,
-                EndpointAllocError::MaxPacketSizeBiggerThanEpFifoSize => UsbError::EndpointMemoryOverflow,
+        alloc_endpoint::alloc_endpoint(&mut self.endpoints, ep_type, index, dir, max_packet_size, AllocOptions::default())
+            .map_err(|e| {
+                warn!("musb/alloc_ep: failed to allocate {:?} {:?} endpoint: {:?}", ep_type, dir, e);
+                match e {
+                    EndpointAllocError::EndpointOverflow => UsbError::EndpointOverflow,
+                    EndpointAllocError::InvalidEndpoint => UsbError::InvalidEndpoint,
+                    #[cfg(not(feature = "_fixed-fifo-size"))]
+                    EndpointAllocError::BufferOverflow => UsbError::EndpointMemoryOverflow,
+                    #[cfg(not(feature = "_fixed-fifo-size"))]
+                    EndpointAllocError::FifoBusy => UsbError::EndpointMemoryOverflow,
+                    EndpointAllocError::EpDirNotSupported => UsbError::InvalidEndpoint,
+                    EndpointAllocError::EpUsed => UsbError::InvalidEndpoint,
+                    EndpointAllocError::MaxPacketSizeBiggerThanEpFifoSize => UsbError::EndpointMemoryOverflow,
+                    EndpointAllocError::InvalidControlMaxPacketSize => UsbError::Unsupported,
+                    EndpointAllocError::MaxPacketSizeExceedsSpeedLimit => UsbError::Unsupported,
+                }
             })
             .map(|index| usb_device::endpoint::EndpointAddress::from_parts(index as usize, ep_dir))
     }
@@ -102,6 +120,8 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
     fn enable(&mut self) {
         trace!("call enable");
         T::regs().faddr().write(|w| w.set_func_addr(0));
+        #[cfg(not(feature = "_lite"))]
+        common_impl::set_soft_connect::<T>(true);
     }
 
     fn reset(&self) {
@@ -109,11 +129,12 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
         T::regs().power().write(|w| w.set_suspend_mode(true));
 
         self.endpoints.iter().enumerate().for_each(|(index, ep)| {
-            if ep.used_tx {
+            let conf = &ep.ep_conf;
+            if ep.used_tx && common_impl::max_packet_size_allowed::<T>(index as _, conf.ep_type, conf.tx_max_packet_size) {
                 trace!("call ep_tx_enable, index = {}", index);
                 common_impl::ep_tx_enable::<T>(index as _, &ep.ep_conf);
             }
-            if ep.used_rx {
+            if ep.used_rx && common_impl::max_packet_size_allowed::<T>(index as _, conf.ep_type, conf.rx_max_packet_size) {
                 trace!("call ep_rx_enable, index = {}", index);
                 common_impl::ep_rx_enable::<T>(index as _, &ep.ep_conf);
             }
@@ -121,6 +142,7 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
 
         self.control_state.set_state(ControlStateEnum::Idle);
         self.control_state.reset_tx_len();
+        self.control_state.reset_rx_len();
     }
 
     fn set_device_address(&self, addr: u8) {
@@ -146,6 +168,9 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
         // if buf.len() > self.endpoints[index].ep_conf.tx_max_packet_size as usize {
         //     return Err(UsbError::BufferOverflow);
         // }
+        if index == 0 && buf.len() > self.endpoints[0].ep_conf.tx_max_packet_size as usize {
+            return Err(UsbError::BufferOverflow);
+        }
         let unready = if index == 0 {
             regs.csr0l().read().tx_pkt_rdy()
         } else {
@@ -155,10 +180,7 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
             return Err(UsbError::WouldBlock);
         }
 
-        if buf.len() != 0 {
-            buf.into_iter()
-                .for_each(|b| regs.fifo(index).write(|w| w.set_data(*b)));
-        }
+        common_impl::fifo_write::<T>(index, buf);
 
         if index == 0 {
             match self.control_state.get_state() {
@@ -239,9 +261,8 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
         //     panic!("read_count > buf.len()");
         //     return Err(UsbError::BufferOverflow);
         // }
-        buf.into_iter()
-            .take(read_count as _)
-            .for_each(|b| *b = regs.fifo(index).read().data());
+        let len = buf.len().min(read_count as usize);
+        common_impl::fifo_read::<T>(index, &mut buf[..len]);
         if index == 0 {
             regs.csr0l().modify(|w| w.set_serviced_rx_pkt_rdy(true));
             match self.control_state.get_state() {
@@ -256,7 +277,7 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
                             self.control_state.set_state(ControlStateEnum::NodataPhase);
                         } else {
                             self.control_state.set_state(ControlStateEnum::DataOut);
-                            // self.control_state.set_rx_len(w_length as _);
+                            self.control_state.set_rx_len(w_length as _);
                         }
                     } else {
                         // IN
@@ -270,12 +291,15 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
                     }
                 }
                 ControlStateEnum::DataOut => {
-                    if (read_count as u32)
-                        < self.endpoints[0].ep_conf.rx_max_packet_size as u32
+                    self.control_state.decrease_rx_len(read_count as u32);
+                    // The host sends no ZLP when wLength is a multiple of the max packet size.
+                    if self.control_state.get_rx_len() == 0
+                        || (read_count as u32) < self.endpoints[0].ep_conf.rx_max_packet_size as u32
                     {
                         // Last Package. include ZLP
                         regs.csr0l().modify(|w| w.set_data_end(true));
                         self.control_state.set_state(ControlStateEnum::Idle);
+                        self.control_state.reset_rx_len();
                         trace!("READ END, buf.len() = {}", buf.len());
                     }
                 }
@@ -305,6 +329,7 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
             if stalled {
                 self.control_state.set_state(ControlStateEnum::Idle);
                 self.control_state.reset_tx_len();
+                self.control_state.reset_rx_len();
             }
         }
     }
@@ -382,6 +407,7 @@ impl<T: MusbInstance> usb_device::bus::UsbBus for UsbdBus<T> {
                                 regs.csr0l().modify(|w| w.set_serviced_setup_end(true));
                                 self.control_state.set_state(ControlStateEnum::Idle);
                                 self.control_state.reset_tx_len();
+                                self.control_state.reset_rx_len();
 
                                 match count {
                                     8 => {